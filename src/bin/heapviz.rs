@@ -0,0 +1,413 @@
+//! Render heap snapshots written by `malloc::dump_heap` as ASCII bars and as an SVG.
+//!
+//! ```text
+//! heapviz [--width COLUMNS] [--svg FILE] [--animate] [--no-color] [SNAPSHOT...]
+//! ```
+//! Each snapshot file holds one dump in either format. Without snapshots, a short random
+//! workload runs on this crate's `malloc` and is dumped after every step. Several snapshots
+//! are printed one after another, or replayed in place with `--animate`, and become the frames
+//! of an animated SVG.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io::{self, IsTerminal, Write};
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+use malloc_rs::malloc::{self, DumpFormat};
+
+const USED_COLOR: &str = "#d9534f";
+const FREE_COLOR: &str = "#5cb85c";
+const SVG_WIDTH: usize = 800;
+const ROW_HEIGHT: usize = 24;
+const LABEL_WIDTH: usize = 80;
+const FRAME_SECONDS: f64 = 0.5;
+
+/// A line of a dump.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Record {
+    arena: usize,
+    offset: usize,
+    data_size: usize,
+    total_size: usize,
+    free: bool,
+}
+
+/// The blocks of each arena at one point in time.
+type Snapshot = BTreeMap<usize, Vec<Record>>;
+
+struct Options {
+    width: usize,
+    svg: Option<String>,
+    animate: bool,
+    color: bool,
+    files: Vec<String>,
+}
+
+fn main() {
+    let options = parse_args().unwrap_or_else(|err| {
+        eprintln!("heapviz: {}", err);
+        eprintln!(
+            "usage: heapviz [--width COLUMNS] [--svg FILE] [--animate] [--no-color] [SNAPSHOT...]"
+        );
+        process::exit(2);
+    });
+
+    let snapshots = if options.files.is_empty() {
+        demo()
+    } else {
+        options
+            .files
+            .iter()
+            .map(|file| {
+                fs::read_to_string(file)
+                    .map_err(|err| err.to_string())
+                    .and_then(|text| parse(&text))
+                    .unwrap_or_else(|err| {
+                        eprintln!("heapviz: {}: {}", file, err);
+                        process::exit(1);
+                    })
+            })
+            .collect()
+    };
+
+    print_ascii(&snapshots, &options);
+    if let Some(path) = &options.svg {
+        if let Err(err) = fs::write(path, svg(&snapshots)) {
+            eprintln!("heapviz: {}: {}", path, err);
+            process::exit(1);
+        }
+    }
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut options = Options {
+        width: 72,
+        svg: None,
+        animate: false,
+        color: io::stdout().is_terminal(),
+        files: Vec::new(),
+    };
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--width" => {
+                options.width = args
+                    .next()
+                    .and_then(|width| width.parse().ok())
+                    .filter(|&width| width > 0)
+                    .ok_or("--width needs a positive number")?;
+            }
+            "--svg" => options.svg = Some(args.next().ok_or("--svg needs a file")?),
+            "--animate" => options.animate = true,
+            "--no-color" => options.color = false,
+            _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+            _ => options.files.push(arg),
+        }
+    }
+    Ok(options)
+}
+
+/// Allocate and free blocks of random sizes, dumping the heap after each step.
+fn demo() -> Vec<Snapshot> {
+    let mut seed: u64 = 0x2545_f491_4f6c_dd1d;
+    let mut random = move |bound: usize| {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        seed as usize % bound
+    };
+
+    // freed blocks should show up as free, not stay in the thread cache
+    malloc::set_tcache_count(0);
+    let mut live: Vec<*mut usize> = Vec::with_capacity(64);
+    let mut snapshots = Vec::new();
+    for step in 0..24 {
+        if step < 8 || random(3) > 0 {
+            for _ in 0..4 {
+                live.push(malloc::malloc(16 + random(512)));
+            }
+        }
+        for _ in 0..2 {
+            if !live.is_empty() {
+                let ptr = live.swap_remove(random(live.len()));
+                malloc::free(ptr);
+            }
+        }
+
+        let mut dump = Vec::with_capacity(1 << 16);
+        malloc::dump_heap(&mut dump, DumpFormat::JsonLines).expect("dump to memory");
+        let text = String::from_utf8(dump).expect("dump is UTF-8");
+        snapshots.push(parse(&text).expect("dump_heap writes valid lines"));
+    }
+    for ptr in live {
+        malloc::free(ptr);
+    }
+    snapshots
+}
+
+/// Read a dump in either `DumpFormat`, told apart by the first line.
+fn parse(text: &str) -> Result<Snapshot, String> {
+    let mut lines = text
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .peekable();
+    let csv = lines.peek().is_some_and(|line| !line.starts_with('{'));
+    if csv {
+        lines.next();
+    }
+
+    let mut snapshot = Snapshot::new();
+    for (number, line) in lines.enumerate() {
+        let record = if csv {
+            parse_csv(line)
+        } else {
+            parse_json(line)
+        };
+        let record = record.ok_or_else(|| format!("malformed block {}: {}", number + 1, line))?;
+        snapshot.entry(record.arena).or_default().push(record);
+    }
+    Ok(snapshot)
+}
+
+fn parse_csv(line: &str) -> Option<Record> {
+    let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+    if fields.len() != 6 {
+        return None;
+    }
+    Some(Record {
+        arena: fields[0].parse().ok()?,
+        offset: fields[2].parse().ok()?,
+        data_size: fields[3].parse().ok()?,
+        total_size: fields[4].parse().ok()?,
+        free: fields[5].parse().ok()?,
+    })
+}
+
+/// Only the flat objects of `dump_heap` are understood, not JSON in general.
+fn parse_json(line: &str) -> Option<Record> {
+    let body = line.trim().strip_prefix('{')?.strip_suffix('}')?;
+    let mut fields = BTreeMap::new();
+    for field in body.split(',') {
+        let (key, value) = field.split_once(':')?;
+        fields.insert(key.trim().trim_matches('"'), value.trim());
+    }
+    Some(Record {
+        arena: fields.get("arena")?.parse().ok()?,
+        offset: fields.get("offset")?.parse().ok()?,
+        data_size: fields.get("data_size")?.parse().ok()?,
+        total_size: fields.get("total_size")?.parse().ok()?,
+        free: fields.get("free")?.parse().ok()?,
+    })
+}
+
+/// Bytes from the first block to the end of the last one of `arena` over all snapshots, so
+/// that every frame is drawn to the same scale.
+fn span(snapshots: &[Snapshot], arena: usize) -> usize {
+    snapshots
+        .iter()
+        .filter_map(|snapshot| snapshot.get(&arena))
+        .flatten()
+        .map(|record| record.offset + record.total_size)
+        .max()
+        .unwrap_or(0)
+}
+
+fn arenas(snapshots: &[Snapshot]) -> Vec<usize> {
+    let mut arenas: Vec<usize> = snapshots.iter().flat_map(|s| s.keys().copied()).collect();
+    arenas.sort_unstable();
+    arenas.dedup();
+    arenas
+}
+
+/// One character per `span / width` bytes: `#` all in use, `.` all free, `+` both.
+fn bar(records: &[Record], span: usize, width: usize, color: bool) -> String {
+    let cell = span.div_ceil(width).max(1);
+    let cells = span.div_ceil(cell);
+    let mut free = vec![0; cells];
+    let mut covered = vec![0; cells];
+    for record in records {
+        let end = record.offset + record.total_size;
+        for (index, (free, covered)) in free.iter_mut().zip(covered.iter_mut()).enumerate() {
+            let overlap = end
+                .min((index + 1) * cell)
+                .saturating_sub(record.offset.max(index * cell));
+            *covered += overlap;
+            if record.free {
+                *free += overlap;
+            }
+        }
+    }
+
+    let mut bar = String::new();
+    for (&free, &covered) in free.iter().zip(&covered) {
+        let (c, code) = match free {
+            _ if covered == 0 => (' ', "0"),
+            0 => ('#', "31"),
+            _ if free == covered => ('.', "32"),
+            _ => ('+', "33"),
+        };
+        if color {
+            bar.push_str(&format!("\x1b[{}m{}\x1b[0m", code, c));
+        } else {
+            bar.push(c);
+        }
+    }
+    bar
+}
+
+fn print_ascii(snapshots: &[Snapshot], options: &Options) {
+    let arenas = arenas(snapshots);
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    for (frame, snapshot) in snapshots.iter().enumerate() {
+        if options.animate && frame > 0 {
+            thread::sleep(Duration::from_secs_f64(FRAME_SECONDS));
+            // back to the top of the previous frame
+            let _ = write!(out, "\x1b[{}A", arenas.len() + 1);
+        }
+        // clear what is left of the previous frame's lines
+        let clear = if options.animate { "\x1b[K" } else { "" };
+        let _ = writeln!(out, "snapshot {}/{}{}", frame + 1, snapshots.len(), clear);
+        for &arena in &arenas {
+            let records = snapshot.get(&arena).map_or(&[][..], Vec::as_slice);
+            let (used, free) = records.iter().fold((0, 0), |(used, free), record| {
+                if record.free {
+                    (used, free + record.data_size)
+                } else {
+                    (used + record.data_size, free)
+                }
+            });
+            let _ = writeln!(
+                out,
+                "arena {:2} |{}| used {} free {}{}",
+                arena,
+                bar(
+                    records,
+                    span(snapshots, arena),
+                    options.width,
+                    options.color
+                ),
+                used,
+                free,
+                clear
+            );
+        }
+        let _ = out.flush();
+    }
+}
+
+/// One row of rectangles per arena. More than one snapshot makes an animation that shows each
+/// in turn, looping.
+fn svg(snapshots: &[Snapshot]) -> String {
+    let arenas = arenas(snapshots);
+    let height = arenas.len().max(1) * ROW_HEIGHT;
+    let width = LABEL_WIDTH + SVG_WIDTH;
+    let mut svg = format!(
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{}" height="{}" font-family="monospace" font-size="12">
+"#,
+        width, height
+    );
+    for (row, &arena) in arenas.iter().enumerate() {
+        svg.push_str(&format!(
+            "<text x=\"4\" y=\"{}\">arena {}</text>\n",
+            row * ROW_HEIGHT + ROW_HEIGHT / 2 + 4,
+            arena
+        ));
+    }
+
+    let frames = snapshots.len();
+    for (frame, snapshot) in snapshots.iter().enumerate() {
+        svg.push_str("<g>\n");
+        if frames > 1 {
+            let values: Vec<&str> = (0..frames)
+                .map(|i| if i == frame { "inline" } else { "none" })
+                .collect();
+            let key_times: Vec<String> = (0..frames)
+                .map(|i| format!("{:.4}", i as f64 / frames as f64))
+                .collect();
+            svg.push_str(&format!(
+                "<animate attributeName=\"display\" calcMode=\"discrete\" dur=\"{}s\" repeatCount=\"indefinite\" values=\"{}\" keyTimes=\"{}\"/>\n",
+                FRAME_SECONDS * frames as f64,
+                values.join(";"),
+                key_times.join(";")
+            ));
+        }
+        for (row, &arena) in arenas.iter().enumerate() {
+            let span = span(snapshots, arena).max(1) as f64;
+            for record in snapshot.get(&arena).into_iter().flatten() {
+                let x = LABEL_WIDTH as f64 + record.offset as f64 / span * SVG_WIDTH as f64;
+                let w = record.total_size as f64 / span * SVG_WIDTH as f64;
+                svg.push_str(&format!(
+                    "<rect x=\"{:.2}\" y=\"{}\" width=\"{:.2}\" height=\"{}\" fill=\"{}\" stroke=\"white\" stroke-width=\"0.5\"><title>offset {} data {} {}</title></rect>\n",
+                    x,
+                    row * ROW_HEIGHT + 2,
+                    w,
+                    ROW_HEIGHT - 4,
+                    if record.free { FREE_COLOR } else { USED_COLOR },
+                    record.offset,
+                    record.data_size,
+                    if record.free { "free" } else { "used" }
+                ));
+            }
+        }
+        svg.push_str("</g>\n");
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bar, parse, svg, Record};
+
+    const CSV: &str = "arena,address,offset,data_size,total_size,free
+0,4096,0,0,24,false
+0,4120,24,32,56,true
+0,4176,80,16,40,false
+";
+
+    #[test]
+    fn test_parse() {
+        let csv = parse(CSV).unwrap();
+        let json = parse(
+            r#"{"arena":0,"address":4096,"offset":0,"data_size":0,"total_size":24,"free":false}
+{"arena":0,"address":4120,"offset":24,"data_size":32,"total_size":56,"free":true}
+{"arena":0,"address":4176,"offset":80,"data_size":16,"total_size":40,"free":false}"#,
+        )
+        .unwrap();
+        assert_eq!(csv, json);
+        assert_eq!(
+            Record {
+                arena: 0,
+                offset: 24,
+                data_size: 32,
+                total_size: 56,
+                free: true,
+            },
+            csv[&0][1]
+        );
+        assert!(parse("arena,address\n0,1,2\n").is_err());
+    }
+
+    #[test]
+    fn test_bar() {
+        let snapshot = parse(CSV).unwrap();
+        // 8 bytes a character
+        assert_eq!("###.......#####", bar(&snapshot[&0], 120, 15, false));
+        // 40 bytes a character
+        assert_eq!("+.#", bar(&snapshot[&0], 120, 3, false));
+    }
+
+    #[test]
+    fn test_svg_frames() {
+        let snapshot = parse(CSV).unwrap();
+        let one = svg(std::slice::from_ref(&snapshot));
+        assert_eq!(3, one.matches("<rect").count());
+        assert!(!one.contains("<animate"));
+        let two = svg(&[snapshot.clone(), snapshot]);
+        assert_eq!(2, two.matches("<animate").count());
+    }
+}
@@ -0,0 +1,386 @@
+//! Replay an allocation trace with each search strategy and compare them.
+//!
+//! ```text
+//! replay [--strategy NAME]... [--no-tcache] TRACE
+//! ```
+//! `TRACE` is either a text file with one call per line, or a binary trace written by
+//! `malloc::start_trace`:
+//! ```text
+//! m <id> <size>    malloc
+//! r <id> <size>    realloc
+//! f <id>           free
+//! ```
+//! Ids name allocations, an id can be reused once freed. Blank lines and lines starting with
+//! `#` are skipped. Every strategy, all five unless `--strategy` is given, replays the trace in
+//! a fresh process of its own, so each starts from an empty heap.
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::process::{self, Command};
+use std::time::Instant;
+
+use malloc_rs::malloc::{self, SearchStrategy, TraceOp, TraceRecord, TRACE_MAGIC};
+
+const STRATEGIES: [&str; 5] = ["FIRST_FIT", "BEST_FIT", "NEXT_FIT", "WORST_FIT", "GOOD_FIT"];
+
+/// A call with its allocation resolved to a slot, dense from 0.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Op {
+    Malloc(usize, usize),
+    Realloc(usize, usize),
+    Free(usize),
+}
+
+/// The outcome of one replay.
+struct Run {
+    nanos: u128,
+    peak_bytes: usize,
+    fragmentation: f64,
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let res = match args.first().map(String::as_str) {
+        Some("--run") => run_child(&args[1..]),
+        _ => compare(&args),
+    };
+    if let Err(err) = res {
+        eprintln!("replay: {}", err);
+        process::exit(1);
+    }
+}
+
+/// Replay in one child process per strategy and print a table of the results.
+fn compare(args: &[String]) -> Result<(), String> {
+    let mut strategies = Vec::new();
+    let mut tcache = true;
+    let mut trace = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--strategy" => {
+                let strategy = args.next().ok_or("--strategy needs a name")?;
+                strategy
+                    .parse::<SearchStrategy>()
+                    .map_err(|_| format!("unknown strategy {}", strategy))?;
+                strategies.push(strategy.as_str());
+            }
+            "--no-tcache" => tcache = false,
+            _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+            _ => trace = Some(arg.as_str()),
+        }
+    }
+    let trace = trace.ok_or("usage: replay [--strategy NAME]... [--no-tcache] TRACE")?;
+    if strategies.is_empty() {
+        strategies.extend(STRATEGIES);
+    }
+    // fail once here rather than in every child
+    let ops = load(trace)?;
+    println!("{} calls from {}", ops.len(), trace);
+
+    let exe = env::current_exe().map_err(|err| err.to_string())?;
+    println!(
+        "{:<10} {:>12} {:>16} {:>14}",
+        "strategy", "time (ms)", "peak heap (KiB)", "fragmentation"
+    );
+    for strategy in strategies {
+        let mut command = Command::new(&exe);
+        command.args(["--run", strategy, trace]);
+        if !tcache {
+            command.arg("--no-tcache");
+        }
+        let output = command.output().map_err(|err| err.to_string())?;
+        if !output.status.success() {
+            return Err(format!(
+                "{} failed: {}",
+                strategy,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        let run = parse_run(&String::from_utf8_lossy(&output.stdout))
+            .ok_or_else(|| format!("{} printed no result", strategy))?;
+        println!(
+            "{:<10} {:>12.3} {:>16.1} {:>14.4}",
+            strategy,
+            run.nanos as f64 / 1e6,
+            run.peak_bytes as f64 / 1024.0,
+            run.fragmentation
+        );
+    }
+    Ok(())
+}
+
+/// `--run STRATEGY TRACE [--no-tcache]`: replay once and print the `Run` on one line.
+fn run_child(args: &[String]) -> Result<(), String> {
+    let (strategy, trace) = match args {
+        [strategy, trace, ..] => (strategy, trace),
+        _ => return Err("--run needs a strategy and a trace".into()),
+    };
+    let strategy = strategy
+        .parse::<SearchStrategy>()
+        .map_err(|_| format!("unknown strategy {}", strategy))?;
+    if args[2..].iter().any(|arg| arg == "--no-tcache") {
+        malloc::set_tcache_count(0);
+    }
+    malloc::set_strategy(strategy);
+
+    let ops = load(trace)?;
+    let run = replay(&ops);
+    println!("{} {} {}", run.nanos, run.peak_bytes, run.fragmentation);
+    Ok(())
+}
+
+fn parse_run(line: &str) -> Option<Run> {
+    let mut fields = line.split_whitespace();
+    Some(Run {
+        nanos: fields.next()?.parse().ok()?,
+        peak_bytes: fields.next()?.parse().ok()?,
+        fragmentation: fields.next()?.parse().ok()?,
+    })
+}
+
+/// Run the calls and measure them. Allocations still live at the end are freed afterwards.
+fn replay(ops: &[Op]) -> Run {
+    let slots = ops
+        .iter()
+        .map(|op| match *op {
+            Op::Malloc(slot, _) | Op::Realloc(slot, _) | Op::Free(slot) => slot + 1,
+        })
+        .max()
+        .unwrap_or(0);
+    let mut live = vec![std::ptr::null_mut(); slots];
+
+    let start = Instant::now();
+    for op in ops {
+        match *op {
+            Op::Malloc(slot, size) => live[slot] = malloc::malloc(size),
+            Op::Realloc(slot, size) => live[slot] = malloc::realloc(live[slot], size),
+            Op::Free(slot) => {
+                malloc::free(live[slot]);
+                live[slot] = std::ptr::null_mut();
+            }
+        }
+    }
+    let nanos = start.elapsed().as_nanos();
+
+    let stats = malloc::malloc_stats();
+    for ptr in live.into_iter().filter(|ptr| !ptr.is_null()) {
+        malloc::free(ptr);
+    }
+    Run {
+        nanos,
+        peak_bytes: stats.peak_arena_bytes,
+        fragmentation: stats.fragmentation(),
+    }
+}
+
+fn load(path: &str) -> Result<Vec<Op>, String> {
+    let bytes = fs::read(path).map_err(|err| format!("{}: {}", path, err))?;
+    let ops = if bytes.starts_with(&TRACE_MAGIC) {
+        let records = malloc::read_trace(&bytes[..]).map_err(|err| err.to_string())?;
+        from_records(records)
+    } else {
+        parse(&String::from_utf8_lossy(&bytes))
+    };
+    ops.map_err(|err| format!("{}: {}", path, err))
+}
+
+/// Resolves allocation ids to slots, reusing the slots of freed allocations.
+#[derive(Default)]
+struct Slots {
+    live: HashMap<u64, usize>,
+    unused: Vec<usize>,
+    count: usize,
+}
+
+impl Slots {
+    fn alloc(&mut self, id: u64) -> Option<usize> {
+        if self.live.contains_key(&id) {
+            return None;
+        }
+        let slot = self.unused.pop().unwrap_or_else(|| {
+            self.count += 1;
+            self.count - 1
+        });
+        self.live.insert(id, slot);
+        Some(slot)
+    }
+
+    fn get(&self, id: u64) -> Option<usize> {
+        self.live.get(&id).copied()
+    }
+
+    /// The allocation `from` moved to `to`, it keeps its slot.
+    fn rename(&mut self, from: u64, to: u64) -> Option<usize> {
+        let slot = self.live.remove(&from)?;
+        self.live.insert(to, slot);
+        Some(slot)
+    }
+
+    fn free(&mut self, id: u64) -> Option<usize> {
+        let slot = self.live.remove(&id)?;
+        self.unused.push(slot);
+        Some(slot)
+    }
+}
+
+/// Read the text format, see the top of this file.
+fn parse(text: &str) -> Result<Vec<Op>, String> {
+    let mut slots = Slots::default();
+    let mut ops = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let error = |message: &str| format!("line {}: {}: {}", number + 1, message, line);
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let id = |at: usize| -> Result<u64, String> {
+            fields
+                .get(at)
+                .and_then(|id| id.parse().ok())
+                .ok_or_else(|| error("expected an id"))
+        };
+        let size = |at: usize| -> Result<usize, String> {
+            fields
+                .get(at)
+                .and_then(|size| size.parse().ok())
+                .filter(|&size| size > 0)
+                .ok_or_else(|| error("expected a positive size"))
+        };
+
+        let op = match fields[0] {
+            "m" if fields.len() == 3 => {
+                let slot = slots
+                    .alloc(id(1)?)
+                    .ok_or_else(|| error("id is already allocated"))?;
+                Op::Malloc(slot, size(2)?)
+            }
+            "r" if fields.len() == 3 => {
+                let slot = slots.get(id(1)?).ok_or_else(|| error("unknown id"))?;
+                Op::Realloc(slot, size(2)?)
+            }
+            "f" if fields.len() == 2 => {
+                Op::Free(slots.free(id(1)?).ok_or_else(|| error("unknown id"))?)
+            }
+            _ => {
+                return Err(error(
+                    "expected `m <id> <size>`, `r <id> <size>` or `f <id>`",
+                ))
+            }
+        };
+        ops.push(op);
+    }
+    Ok(ops)
+}
+
+/// Turn a binary trace into calls, with the pointers as ids. Frees of memory allocated before
+/// the trace started are skipped, `calloc` and `memalign` become plain allocations.
+fn from_records(mut records: Vec<TraceRecord>) -> Result<Vec<Op>, String> {
+    // threads wrote their records in batches
+    records.sort_by_key(|record| record.timestamp);
+
+    let mut slots = Slots::default();
+    let mut ops = Vec::new();
+    for record in records {
+        let size = match record.op {
+            TraceOp::Calloc => record.size * record.arg,
+            _ => record.size,
+        } as usize;
+        match record.op {
+            TraceOp::Malloc | TraceOp::Calloc | TraceOp::Memalign if record.ptr != 0 => {
+                let slot = slots
+                    .alloc(record.ptr)
+                    .ok_or_else(|| format!("{:#x} allocated twice", record.ptr))?;
+                ops.push(Op::Malloc(slot, size));
+            }
+            TraceOp::Realloc if record.ptr != 0 => match slots.rename(record.arg, record.ptr) {
+                Some(slot) => ops.push(Op::Realloc(slot, size)),
+                None if record.arg == 0 => {
+                    let slot = slots
+                        .alloc(record.ptr)
+                        .ok_or_else(|| format!("{:#x} allocated twice", record.ptr))?;
+                    ops.push(Op::Malloc(slot, size));
+                }
+                None => {}
+            },
+            // `realloc(ptr, 0)` frees
+            TraceOp::Realloc if record.size == 0 => {
+                if let Some(slot) = slots.free(record.arg) {
+                    ops.push(Op::Free(slot));
+                }
+            }
+            TraceOp::Free => {
+                if let Some(slot) = slots.free(record.ptr) {
+                    ops.push(Op::Free(slot));
+                }
+            }
+            // failed calls
+            _ => {}
+        }
+    }
+    Ok(ops)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{from_records, parse, Op};
+    use malloc_rs::malloc::{TraceOp, TraceRecord};
+
+    #[test]
+    fn test_parse() {
+        let ops = parse("# comment\nm 7 100\nm 8 16\n\nr 7 200\nf 7\nm 9 32\nf 8\n").unwrap();
+        assert_eq!(
+            vec![
+                Op::Malloc(0, 100),
+                Op::Malloc(1, 16),
+                Op::Realloc(0, 200),
+                Op::Free(0),
+                // reuses the slot of 7
+                Op::Malloc(0, 32),
+                Op::Free(1),
+            ],
+            ops
+        );
+
+        assert!(parse("f 1\n").unwrap_err().contains("line 1: unknown id"));
+        assert!(parse("m 1 8\nm 1 8\n").is_err());
+        assert!(parse("m 1 0\n").is_err());
+        assert!(parse("x 1\n").is_err());
+    }
+
+    #[test]
+    fn test_from_records() {
+        let record = |timestamp, op, size, arg, ptr| TraceRecord {
+            timestamp,
+            thread: 1,
+            op,
+            flags: 0,
+            size,
+            arg,
+            ptr,
+            block: 0,
+        };
+        let ops = from_records(vec![
+            record(4, TraceOp::Free, 0, 0, 0x2000),
+            record(1, TraceOp::Malloc, 64, 0, 0x1000),
+            record(2, TraceOp::Calloc, 8, 4, 0x2000),
+            record(3, TraceOp::Realloc, 128, 0x1000, 0x3000),
+            record(5, TraceOp::Free, 0, 0, 0x3000),
+            // allocated before the trace
+            record(6, TraceOp::Free, 0, 0, 0x4000),
+        ])
+        .unwrap();
+        assert_eq!(
+            vec![
+                Op::Malloc(0, 64),
+                Op::Malloc(1, 32),
+                Op::Realloc(0, 128),
+                Op::Free(1),
+                Op::Free(0),
+            ],
+            ops
+        );
+    }
+}
@@ -1,6 +1,6 @@
-#![feature(llvm_asm)]
-#![feature(mutex_unlock)]
 #![recursion_limit = "256"]
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
 
 pub mod malloc;
+#[cfg(feature = "std")]
 pub mod queue;
@@ -3,6 +3,7 @@ use std::thread;
 use std::time;
 use uuid::Uuid;
 
+use malloc_rs::malloc::{self, SearchStrategy};
 use malloc_rs::queue::Queue;
 
 #[repr(transparent)]
@@ -13,6 +14,13 @@ fn main() {
 
     let jps = args[1].parse::<u32>().unwrap();
     let num_workers = args[2].parse::<u32>().unwrap();
+    if let Some(strategy) = args.get(3) {
+        malloc::set_strategy(
+            strategy
+                .parse::<SearchStrategy>()
+                .unwrap_or(SearchStrategy::FirstFit),
+        );
+    }
 
     let mut works = Queue::<Work>::new();
     // Use of unsafe to share pointers between threads
@@ -38,9 +46,8 @@ fn main() {
     for i in 0..num_workers {
         threads.push(thread::spawn(move || loop {
             let works = unsafe { &mut *(ptr as *mut Queue<Work>) };
-            let pop = works.pop();
-            if pop.is_some() {
-                println!("Worker {:?} pops {:?}", i, pop.unwrap().0)
+            if let Some(work) = works.pop() {
+                println!("Worker {:?} pops {:?}", i, work.0)
             }
             let dur = time::Duration::from_millis(100);
             thread::sleep(dur);
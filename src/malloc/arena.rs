@@ -0,0 +1,326 @@
+#[cfg(feature = "std")]
+use core::cell::Cell;
+use core::mem::{self, size_of};
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::heap::Heap;
+use super::lock::{SpinLock, SpinLockGuard};
+use super::source::{Brk, MemorySource, MmapRegion, Slice};
+use super::types::Block;
+
+/// Upper bound for `set_arena_max`.
+pub const MAX_ARENAS: usize = 16;
+
+/// Arenas threads spread over once they contend, including the main arena, like glibc's
+/// `M_ARENA_MAX`.
+pub const DEFAULT_ARENA_MAX: usize = 8;
+static ARENA_MAX: AtomicUsize = AtomicUsize::new(DEFAULT_ARENA_MAX);
+
+/// Arena handed to the next thread that finds its own arena locked.
+static NEXT_ARENA: AtomicUsize = AtomicUsize::new(1);
+
+/// Size and alignment of the mmapped heap of a non-main arena. Only the pages that are touched
+/// take up memory.
+pub const HEAP_SIZE: usize = 4 * 1024 * 1024;
+
+/// Arena 0 is the main arena.
+static ARENAS: [SpinLock<Arena>; MAX_ARENAS] = arenas();
+
+/// Start and end of the memory of each arena, updated as it grows and shrinks, so `free` can
+/// check a pointer without taking a lock. Both are 0 until the arena first allocates.
+static BOUNDS: [(AtomicUsize, AtomicUsize); MAX_ARENAS] =
+    [const { (AtomicUsize::new(0), AtomicUsize::new(0)) }; MAX_ARENAS];
+
+#[cfg(feature = "std")]
+std::thread_local! {
+    /// Index of the arena this thread allocates from.
+    static ARENA_INDEX: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Written at the start of every non-main heap, so `free` can find the arena of a block.
+#[repr(C)]
+struct HeapInfo {
+    index: usize,
+}
+
+/// An independent heap with its own block list, free lists and lock.
+///
+/// The main arena grows the program break. Every other arena lives in a single mmapped heap
+/// of `HEAP_SIZE` bytes, aligned to its size, and flags its blocks as non-main.
+pub type Arena = Heap<ArenaMemory>;
+
+pub enum ArenaMemory {
+    Brk(Brk),
+    /// A caller-provided buffer in place of the program break, see `set_heap_buffer`.
+    Buffer(Slice<'static>),
+    /// Mapped on first use, starting with its `HeapInfo`.
+    Mmap {
+        index: usize,
+        region: Option<MmapRegion>,
+    },
+}
+
+const fn arenas() -> [SpinLock<Arena>; MAX_ARENAS] {
+    let mut arenas = [const { SpinLock::new(Heap::new(ArenaMemory::Brk(Brk))) }; MAX_ARENAS];
+    let mut index = 1;
+    while index < MAX_ARENAS {
+        let memory = ArenaMemory::Mmap {
+            index,
+            region: None,
+        };
+        let arena = SpinLock::new(Heap::with_arena_flag(memory, true));
+        // the replaced arena holds no mapping
+        mem::forget(mem::replace(&mut arenas[index], arena));
+        index += 1;
+    }
+    arenas
+}
+
+/// Let threads spread over at most `max` arenas, clamped to `1..=MAX_ARENAS`. One keeps every
+/// allocation in the main arena.
+pub fn set_arena_max(max: usize) {
+    ARENA_MAX.store(max.clamp(1, MAX_ARENAS), Ordering::Relaxed);
+}
+
+pub fn main() -> &'static SpinLock<Arena> {
+    &ARENAS[0]
+}
+
+/// Every arena, the main arena first. Non-main arenas that never allocated have no heap yet.
+pub fn all() -> &'static [SpinLock<Arena>] {
+    &ARENAS
+}
+
+/// The arena `block` belongs to, found through its header.
+pub fn of(block: &Block) -> &'static SpinLock<Arena> {
+    if !block.header().is_non_main_arena() {
+        return main();
+    }
+
+    let heap = block.0 as usize & !(HEAP_SIZE - 1);
+    // the heap starts with its `HeapInfo`
+    &ARENAS[unsafe { (*(heap as *const HeapInfo)).index }]
+}
+
+/// Whether `block` lies in the heap of an arena, past its root block, e.g. between the root and
+/// the program break for the main arena.
+pub fn contains(block: &Block) -> bool {
+    let address = block.0 as usize;
+    BOUNDS.iter().any(|(start, end)| {
+        let start = start.load(Ordering::Acquire);
+        start != 0
+            && address > start
+            && address + Block::get_total_padding() <= end.load(Ordering::Acquire)
+    })
+}
+
+/// Serve the main arena from `buffer` instead of the program break. Returns false if the main
+/// arena already allocated.
+pub fn set_main_buffer(buffer: &'static mut [u8]) -> bool {
+    let mut arena = main().lock();
+    if arena.is_initialized() {
+        return false;
+    }
+    *arena.source_mut() = ArenaMemory::Buffer(Slice::new(buffer));
+    true
+}
+
+/// Lock the arena of the current thread.
+///
+/// Threads start in the main arena. A thread that finds its arena locked by another thread
+/// moves on to the next arena, round-robin, and stays there.
+pub fn lock() -> SpinLockGuard<'static, Arena> {
+    let index = thread_arena();
+    if let Some(arena) = ARENAS[index].try_lock() {
+        return arena;
+    }
+
+    let max = ARENA_MAX.load(Ordering::Relaxed);
+    let mut next = NEXT_ARENA.fetch_add(1, Ordering::Relaxed) % max;
+    if next == index {
+        next = (next + 1) % max;
+    }
+    if !set_thread_arena(next) {
+        return ARENAS[index].lock();
+    }
+    ARENAS[next].lock()
+}
+
+#[cfg(feature = "std")]
+fn thread_arena() -> usize {
+    // the thread local is gone while the thread exits
+    ARENA_INDEX.try_with(Cell::get).unwrap_or(0)
+}
+
+#[cfg(feature = "std")]
+fn set_thread_arena(index: usize) -> bool {
+    ARENA_INDEX.try_with(|arena| arena.set(index)).is_ok()
+}
+
+/// Without std there are no thread locals, every thread stays in the main arena.
+#[cfg(not(feature = "std"))]
+fn thread_arena() -> usize {
+    0
+}
+
+#[cfg(not(feature = "std"))]
+fn set_thread_arena(_index: usize) -> bool {
+    false
+}
+
+impl ArenaMemory {
+    pub fn is_main(&self) -> bool {
+        !matches!(self, ArenaMemory::Mmap { .. })
+    }
+
+    fn bounds(&self) -> &'static (AtomicUsize, AtomicUsize) {
+        match self {
+            ArenaMemory::Mmap { index, .. } => &BOUNDS[*index],
+            _ => &BOUNDS[0],
+        }
+    }
+}
+
+impl MemorySource for ArenaMemory {
+    fn extend(&mut self, increment: usize) -> *mut u8 {
+        let res = match self {
+            ArenaMemory::Brk(brk) => brk.extend(increment),
+            ArenaMemory::Buffer(buffer) => buffer.extend(increment),
+            ArenaMemory::Mmap { index, region } => {
+                if region.is_none() {
+                    let mut heap = match MmapRegion::aligned(HEAP_SIZE, HEAP_SIZE) {
+                        Some(heap) => heap,
+                        None => return ptr::null_mut(),
+                    };
+                    let info = heap.extend(size_of::<HeapInfo>()) as *mut HeapInfo;
+                    unsafe { *info = HeapInfo { index: *index } };
+                    *region = Some(heap);
+                }
+                region.as_mut().unwrap().extend(increment)
+            }
+        };
+        if !res.is_null() {
+            let (start, end) = self.bounds();
+            // the first extension holds the root block
+            let _ = start.compare_exchange(0, res as usize, Ordering::AcqRel, Ordering::Acquire);
+            end.store(res as usize + increment, Ordering::Release);
+        }
+        res
+    }
+
+    fn top(&self) -> usize {
+        match self {
+            ArenaMemory::Brk(brk) => brk.top(),
+            ArenaMemory::Buffer(buffer) => buffer.top(),
+            ArenaMemory::Mmap { region, .. } => region.as_ref().map_or(0, MmapRegion::top),
+        }
+    }
+
+    fn shrink(&mut self, top: usize) -> bool {
+        let shrunk = match self {
+            ArenaMemory::Brk(brk) => brk.shrink(top),
+            ArenaMemory::Buffer(buffer) => buffer.shrink(top),
+            ArenaMemory::Mmap { region, .. } => {
+                region.as_mut().is_some_and(|heap| heap.shrink(top))
+            }
+        };
+        if shrunk {
+            self.bounds().1.store(top, Ordering::Release);
+        }
+        shrunk
+    }
+
+    fn is_zeroed(&self) -> bool {
+        match self {
+            ArenaMemory::Brk(brk) => brk.is_zeroed(),
+            ArenaMemory::Buffer(buffer) => buffer.is_zeroed(),
+            ArenaMemory::Mmap { .. } => true,
+        }
+    }
+}
+
+// threads only move between arenas with std
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use std::ptr;
+    use std::sync::{Arc, Barrier};
+    use std::thread;
+
+    use super::{main, of, set_arena_max, DEFAULT_ARENA_MAX, HEAP_SIZE};
+    use crate::malloc::tests::MUTEX;
+    use crate::malloc::types::Data;
+    use crate::malloc::{free, malloc, set_mmap_threshold, DEFAULT_MMAP_THRESHOLD};
+
+    #[test]
+    fn test_contended_thread_moves_to_other_arena() {
+        let _lock = MUTEX.lock().unwrap();
+        set_arena_max(DEFAULT_ARENA_MAX);
+        let guard = main().lock();
+        let (tmp, again) = thread::spawn(|| (malloc(64) as usize, malloc(64) as usize))
+            .join()
+            .unwrap();
+        drop(guard);
+
+        let block = Data(tmp as *mut usize).get_block();
+        assert!(block.header().is_non_main_arena());
+        assert!(!ptr::eq(main(), of(&block)));
+        // the thread stayed in its new arena
+        assert!(ptr::eq(
+            of(&block),
+            of(&Data(again as *mut usize).get_block())
+        ));
+        free(tmp as *mut usize);
+        free(again as *mut usize);
+    }
+
+    #[test]
+    fn test_single_arena() {
+        let _lock = MUTEX.lock().unwrap();
+        set_arena_max(1);
+        let guard = main().lock();
+        let waiter = thread::spawn(|| malloc(64) as usize);
+        drop(guard);
+
+        let tmp = waiter.join().unwrap();
+        assert!(!Data(tmp as *mut usize)
+            .get_block()
+            .header()
+            .is_non_main_arena());
+        free(tmp as *mut usize);
+        set_arena_max(DEFAULT_ARENA_MAX);
+    }
+
+    #[test]
+    fn test_exhausted_heap_falls_back_to_main_arena() {
+        let _lock = MUTEX.lock().unwrap();
+        set_arena_max(DEFAULT_ARENA_MAX);
+        set_mmap_threshold(usize::MAX);
+        let barrier = Arc::new(Barrier::new(2));
+        let guard = main().lock();
+        let waiter = {
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                let small = malloc(8);
+                barrier.wait();
+                let large = malloc(HEAP_SIZE);
+                (small as usize, large as usize)
+            })
+        };
+        barrier.wait();
+        drop(guard);
+
+        let (small, large) = waiter.join().unwrap();
+        assert!(Data(small as *mut usize)
+            .get_block()
+            .header()
+            .is_non_main_arena());
+        assert!(!Data(large as *mut usize)
+            .get_block()
+            .header()
+            .is_non_main_arena());
+        free(small as *mut usize);
+        free(large as *mut usize);
+        set_mmap_threshold(DEFAULT_MMAP_THRESHOLD);
+    }
+}
@@ -0,0 +1,166 @@
+use super::types::Block;
+
+/// Number of size classes. Class `i` holds free blocks with a data size in `[2^(i+4), 2^(i+5))`,
+/// the last class also holds everything larger.
+pub const NUM_BINS: usize = 40;
+
+/// Heads of the explicit, doubly-linked free lists, one per size class.
+/// The links live in the data of each free block, see `FreeLinks`.
+pub struct Bins {
+    heads: [Block; NUM_BINS],
+    /// Where `next_fit` resumes: a size class and the block of its list after the one
+    /// allocated last, null at the end of the list.
+    rover: (usize, Block),
+}
+
+impl Bins {
+    pub const fn new() -> Bins {
+        Bins {
+            heads: [Block::null(); NUM_BINS],
+            rover: (0, Block::null()),
+        }
+    }
+
+    /// Size class of a data size, e.g. 16..=31 -> 0, 32..=63 -> 1.
+    fn index(data_size: usize) -> usize {
+        let log2 = (usize::BITS - 1 - data_size.leading_zeros()) as usize;
+        log2.saturating_sub(4).min(NUM_BINS - 1)
+    }
+
+    /// Push a free block to the front of its size class.
+    pub fn insert(&mut self, block: &Block) {
+        let head = &mut self.heads[Self::index(block.get_data_size())];
+        let links = block.links();
+        links.prev_free = Block::null();
+        links.next_free = *head;
+        if !head.is_null() {
+            head.links().prev_free = *block;
+        }
+        *head = *block;
+    }
+
+    /// Unlink a free block. Must be called before its size changes.
+    pub fn remove(&mut self, block: &Block) {
+        let links = block.links();
+        let index = Self::index(block.get_data_size());
+        if self.rover == (index, *block) {
+            self.rover.1 = links.next_free;
+        }
+        if links.prev_free.is_null() {
+            self.heads[index] = links.next_free;
+        } else {
+            links.prev_free.links().next_free = links.next_free;
+        }
+        if !links.next_free.is_null() {
+            links.next_free.links().prev_free = links.prev_free;
+        }
+    }
+
+    /// Returns the first free block with at least `data_size` bytes of data.
+    /// Every block of a larger size class fits, so only the first class is walked past its head.
+    pub fn first_fit(&self, data_size: usize) -> Option<Block> {
+        for head in &self.heads[Self::index(data_size)..] {
+            let mut current = *head;
+            while !current.is_null() {
+                if current.get_data_size() >= data_size {
+                    return Some(current);
+                }
+                current = current.links().next_free;
+            }
+        }
+        None
+    }
+
+    /// Returns the smallest free block with at least `data_size` bytes of data, or the first one
+    /// found with at most `slack` bytes to spare.
+    /// Size classes don't overlap, so the first class with a fitting block holds the best one.
+    pub fn best_fit(&self, data_size: usize, slack: usize) -> Option<Block> {
+        for head in &self.heads[Self::index(data_size)..] {
+            let mut best: Option<Block> = None;
+            let mut current = *head;
+            while !current.is_null() {
+                let current_size = current.get_data_size();
+                if current_size >= data_size
+                    && best.is_none_or(|block| current_size < block.get_data_size())
+                {
+                    best = Some(current);
+                    if current_size - data_size <= slack {
+                        break;
+                    }
+                }
+                current = current.links().next_free;
+            }
+
+            if best.is_some() {
+                return best;
+            }
+        }
+        None
+    }
+
+    /// Returns the first free block with at least `data_size` bytes of data after the rover,
+    /// wrapping around once. The lists are walked one size class after the other. The rover
+    /// stays on the block found and moves past it once it is removed.
+    pub fn next_fit(&mut self, data_size: usize) -> Option<Block> {
+        let block = self.next_fit_from_rover(data_size)?;
+        self.rover = (Self::index(block.get_data_size()), block);
+        Some(block)
+    }
+
+    fn next_fit_from_rover(&self, data_size: usize) -> Option<Block> {
+        let first = Self::index(data_size);
+        let (index, rover) = self.rover;
+        // the classes from the rover up to `first` hold no block that fits
+        if index < first {
+            return self.first_fit(data_size);
+        }
+
+        let fits = |mut current: Block, end: Block| {
+            while current != end {
+                if current.get_data_size() >= data_size {
+                    return Some(current);
+                }
+                current = current.links().next_free;
+            }
+            None
+        };
+        fits(rover, Block::null())
+            .or_else(|| {
+                self.heads[index + 1..]
+                    .iter()
+                    .chain(&self.heads[first..index])
+                    .find_map(|head| fits(*head, Block::null()))
+            })
+            .or_else(|| fits(self.heads[index], rover))
+    }
+
+    /// Returns the largest free block if it has at least `data_size` bytes of data.
+    /// Only the highest non-empty size class can hold it.
+    pub fn worst_fit(&self, data_size: usize) -> Option<Block> {
+        let head = self.heads.iter().rev().find(|head| !head.is_null())?;
+        let mut worst = *head;
+        let mut current = head.links().next_free;
+        while !current.is_null() {
+            if current.get_data_size() > worst.get_data_size() {
+                worst = current;
+            }
+            current = current.links().next_free;
+        }
+
+        (worst.get_data_size() >= data_size).then_some(worst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Bins;
+
+    #[test]
+    fn test_index() {
+        assert_eq!(0, Bins::index(16));
+        assert_eq!(0, Bins::index(31));
+        assert_eq!(1, Bins::index(32));
+        assert_eq!(6, Bins::index(1024));
+        assert_eq!(super::NUM_BINS - 1, Bins::index(usize::MAX));
+    }
+}
@@ -0,0 +1,400 @@
+use core::mem::size_of;
+use core::ptr;
+
+use super::align;
+use super::lock::SpinLock;
+use super::source::{Brk, MemorySource};
+use super::types::{Block, Data, Header};
+
+/// Smallest block: a header and one word of data.
+const MIN_ORDER: usize = 5;
+const NUM_ORDERS: usize = usize::BITS as usize;
+
+/// Serves `malloc` and friends once `Backend::Buddy` is selected.
+static BUDDY: SpinLock<Buddy<Brk>> = SpinLock::new(Buddy::new(Brk));
+
+/// A binary buddy allocator over the memory of `S`, an alternative to the block list of `Heap`.
+///
+/// Every block spans a power of two bytes at a multiple of its size from the start of the
+/// memory. A block of order `k` is split into two buddies of order `k - 1`, and a freed block
+/// merges with its buddy, found by flipping bit `k - 1` of its offset, as long as that one is
+/// free too. The memory doubles whenever no free block is large enough, the new half being the
+/// buddy of everything below it.
+///
+/// Blocks carry the same `Header` as blocks of a `Heap`. The links are the free list of its
+/// order while the block is free, and `prev` is null while it is in use.
+///
+/// ```
+/// use malloc_rs::malloc::{Buddy, MmapRegion};
+///
+/// let mut buddy = Buddy::new(MmapRegion::new(1 << 20).unwrap());
+/// let ptr = buddy.malloc(64);
+/// buddy.free(ptr);
+/// ```
+pub struct Buddy<S: MemorySource> {
+    source: S,
+    base: usize,
+    /// Bytes taken from the source, a power of two once initialized.
+    size: usize,
+    free_lists: [Block; NUM_ORDERS],
+}
+
+// blocks are only reachable through the allocator
+unsafe impl<S: MemorySource + Send> Send for Buddy<S> {}
+
+pub fn global() -> &'static SpinLock<Buddy<Brk>> {
+    &BUDDY
+}
+
+/// Order of the smallest block with room for `data_size` bytes of data.
+fn order(data_size: usize) -> Option<usize> {
+    let total_size = data_size.checked_add(Block::get_total_padding())?;
+    let order = (usize::BITS - (total_size - 1).leading_zeros()) as usize;
+    (order < NUM_ORDERS).then_some(order.max(MIN_ORDER))
+}
+
+fn order_of(block: &Block) -> usize {
+    block.get_total_size().trailing_zeros() as usize
+}
+
+impl<S: MemorySource> Buddy<S> {
+    /// Create an empty allocator, no memory is taken from `source` until the first allocation.
+    pub const fn new(source: S) -> Buddy<S> {
+        Buddy {
+            source,
+            base: 0,
+            size: 0,
+            free_lists: [Block::null(); NUM_ORDERS],
+        }
+    }
+
+    pub fn source(&self) -> &S {
+        &self.source
+    }
+
+    pub(super) fn is_initialized(&self) -> bool {
+        self.size != 0
+    }
+
+    /// Returns null if the source is exhausted.
+    pub fn malloc(&mut self, size: usize) -> *mut usize {
+        assert!(size > 0);
+        match order(align(size)).and_then(|order| self.take(order)) {
+            Some(block) => block.data().unwrap().0,
+            None => ptr::null_mut(),
+        }
+    }
+
+    /// Allocate zero-initialized memory for `count` elements of `size` bytes each.
+    /// Returns null if `count * size` overflows or is zero, or if the source is exhausted.
+    ///
+    /// Split blocks leave headers behind in fresh memory, so the data is always cleared.
+    pub fn calloc(&mut self, count: usize, size: usize) -> *mut usize {
+        let total_size = match count.checked_mul(size) {
+            None | Some(0) => return ptr::null_mut(),
+            Some(total_size) => total_size,
+        };
+
+        let res = self.malloc(total_size);
+        if !res.is_null() {
+            unsafe { ptr::write_bytes(res as *mut u8, 0, total_size) };
+        }
+        res
+    }
+
+    /// Allocate `size` bytes at a multiple of `alignment`, which must be a power of two.
+    /// Returns null if `size` is zero, `alignment` is invalid or the source is exhausted.
+    ///
+    /// The data of a block rarely starts aligned, so the block has room for a second header
+    /// right before the aligned data. Its `prev` leads `free` to the actual block.
+    pub fn memalign(&mut self, alignment: usize, size: usize) -> *mut usize {
+        if size == 0 || !alignment.is_power_of_two() {
+            return ptr::null_mut();
+        }
+        if alignment <= size_of::<usize>() {
+            return self.malloc(size);
+        }
+
+        let padding = alignment + Block::get_total_padding();
+        let block = match align(size)
+            .checked_add(padding)
+            .and_then(order)
+            .and_then(|order| self.take(order))
+        {
+            Some(block) => block,
+            None => return ptr::null_mut(),
+        };
+
+        let data = block.data().unwrap().0 as usize;
+        if data.is_multiple_of(alignment) {
+            return data as *mut usize;
+        }
+        let aligned = (data + Block::get_total_padding() + alignment - 1) & !(alignment - 1);
+        let inner = Data(aligned as *mut usize).get_block();
+        let end = block.0 as usize + block.get_total_size();
+        unsafe { *inner.0 = Header::from_usize(end - aligned) };
+        inner.header().set_prev(block);
+        aligned as *mut usize
+    }
+
+    /// Resize the allocation at `ptr` to `size` bytes. Shrinking, or growing within the block,
+    /// keeps `ptr`, otherwise the data moves to a new block.
+    /// A null `ptr` behaves like `malloc`, a zero `size` frees `ptr`, if any, and returns null.
+    /// Returns null, leaving `ptr` untouched, if the source is exhausted.
+    pub fn realloc(&mut self, ptr: *mut usize, size: usize) -> *mut usize {
+        if size == 0 {
+            if !ptr.is_null() {
+                self.free(ptr);
+            }
+            return ptr::null_mut();
+        }
+        if ptr.is_null() {
+            return self.malloc(size);
+        }
+
+        let available = Data(ptr).get_block().get_data_size();
+        if align(size) <= available {
+            return ptr;
+        }
+
+        let new = self.malloc(size);
+        if !new.is_null() {
+            unsafe { ptr::copy_nonoverlapping(ptr as *const u8, new as *mut u8, available) };
+            self.free(ptr);
+        }
+        new
+    }
+
+    /// Whether `block` lies in the memory of this allocator.
+    pub(super) fn contains(&self, block: &Block) -> bool {
+        let address = block.0 as usize;
+        self.size != 0
+            && address >= self.base
+            && address + Block::get_total_padding() <= self.base + self.size
+    }
+
+    pub fn free(&mut self, ptr: *mut usize) {
+        assert!(ptr as usize != 0);
+        let mut block = Data(ptr).get_block();
+        if block.has_prev() {
+            // an aligned allocation inside `prev`, whose header has to show the pointer is free
+            block.set_free(true);
+            block = *block.prev();
+        }
+        let order = order_of(&block);
+        self.release(block, order);
+    }
+
+    /// Merge a free block of `order` with its buddy as long as that one is free too, then put
+    /// the result on its free list.
+    fn release(&mut self, mut block: Block, mut order: usize) {
+        loop {
+            let buddy_offset = (block.0 as usize - self.base) ^ (1 << order);
+            // the top block has no buddy until the memory grows
+            if buddy_offset >= self.size {
+                break;
+            }
+            let buddy = Block::from_usize(self.base + buddy_offset);
+            // the buddy may be split or in use
+            if !buddy.is_free() || order_of(&buddy) != order {
+                break;
+            }
+
+            self.remove(&buddy, order);
+            block = Block::from_usize(block.0.min(buddy.0) as usize);
+            order += 1;
+        }
+        self.push(&block, order);
+    }
+
+    /// Take a free block of `order`, splitting a larger one or growing the memory if needed.
+    fn take(&mut self, order: usize) -> Option<Block> {
+        let found = loop {
+            if let Some(found) = (order..NUM_ORDERS).find(|&o| !self.free_lists[o].is_null()) {
+                break found;
+            }
+            if !self.grow(order) {
+                return None;
+            }
+        };
+
+        let block = self.free_lists[found];
+        self.remove(&block, found);
+        for lower in (order..found).rev() {
+            // keep the lower half, free the upper one
+            self.push(&Block::from_usize(block.0 as usize + (1 << lower)), lower);
+        }
+
+        unsafe { *block.0 = Header::from_usize((1 << order) - Block::get_total_padding()) };
+        Some(block)
+    }
+
+    /// Double the memory, or take the first block of `order` from the source. The new memory is
+    /// a free block. Returns false if the source is exhausted.
+    fn grow(&mut self, order: usize) -> bool {
+        let increment = if self.is_initialized() {
+            self.size
+        } else {
+            1 << order
+        };
+        let new = self.source.extend(increment);
+        if new.is_null() {
+            return false;
+        }
+        if !self.is_initialized() {
+            self.base = new as usize;
+        } else if new as usize != self.base + self.size {
+            // somebody else moved the top, the offsets would no longer line up. The memory is
+            // untouched, give it back, a source that cannot shrink keeps it.
+            self.source.shrink(new as usize);
+            return false;
+        }
+
+        self.size += increment;
+        // the new half is the buddy of the old memory, which may be one free block
+        self.release(
+            Block::from_usize(new as usize),
+            increment.trailing_zeros() as usize,
+        );
+        true
+    }
+
+    /// Write the header of a free block of `order` and put it at the front of its free list.
+    fn push(&mut self, block: &Block, order: usize) {
+        unsafe { *block.0 = Header::from_usize((1 << order) - Block::get_total_padding()) };
+        block.set_free(true);
+        let head = self.free_lists[order];
+        block.header().set_next(head);
+        if !head.is_null() {
+            head.header().set_prev(*block);
+        }
+        self.free_lists[order] = *block;
+    }
+
+    /// Unlink a free block of `order`. Clears `prev`, which marks the block as not aligned once
+    /// it is handed out.
+    fn remove(&mut self, block: &Block, order: usize) {
+        let prev = *block.prev();
+        let next = *block.next();
+        if prev.is_null() {
+            self.free_lists[order] = next;
+        } else {
+            prev.header().set_next(next);
+        }
+        if !next.is_null() {
+            next.header().set_prev(prev);
+        }
+        block.header().set_prev(Block::null());
+        block.header().set_next(Block::null());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Buddy;
+    use crate::malloc::source::{MemorySource, MmapRegion, Slice};
+    use crate::malloc::types::{Block, Data};
+
+    fn buddy() -> Buddy<MmapRegion> {
+        Buddy::new(MmapRegion::new(64 << 20).unwrap())
+    }
+
+    #[test]
+    fn test_split_and_merge() {
+        let mut buddy = buddy();
+
+        let first = buddy.malloc(8);
+        let block = Data(first).get_block();
+        assert_eq!(32, block.get_total_size());
+        assert_eq!(buddy.source().top(), block.0 as usize + 32);
+
+        // the buddy of the first block
+        let second = buddy.malloc(8);
+        assert_eq!(first as usize + 32, second as usize);
+        // doubles the memory again, the new half fits exactly
+        let third = buddy.malloc(40);
+        assert_eq!(first as usize + 64, third as usize);
+        assert_eq!(buddy.source().top(), block.0 as usize + 128);
+
+        buddy.free(second);
+        buddy.free(third);
+        buddy.free(first);
+        // everything merged back into one block
+        let whole = buddy.malloc(128 - Block::get_total_padding());
+        assert_eq!(first, whole);
+        assert_eq!(buddy.source().top(), block.0 as usize + 128);
+    }
+
+    #[test]
+    fn test_grow_merges_new_half() {
+        let mut buddy = buddy();
+
+        let small = buddy.malloc(8);
+        let start = Data(small).get_block().0 as usize;
+        buddy.free(small);
+        // merged with the free memory below it twice, into one block of 128 bytes
+        let first = buddy.malloc(100);
+        assert_eq!(small, first);
+        assert_eq!(buddy.source().top(), start + 128);
+        buddy.free(first);
+        assert_eq!(first, buddy.malloc(100));
+        assert_eq!(buddy.source().top(), start + 128);
+    }
+
+    #[test]
+    fn test_memalign() {
+        let mut buddy = buddy();
+        let _ = buddy.malloc(8);
+
+        let aligned = buddy.memalign(4096, 100);
+        assert_eq!(0, aligned as usize % 4096);
+        unsafe { (aligned as *mut u8).write_bytes(0xab, 100) };
+        let block = Data(aligned).get_block();
+        assert!(block.has_prev());
+
+        buddy.free(aligned);
+        assert!(block.is_free());
+        let again = buddy.memalign(4096, 100);
+        assert_eq!(aligned, again);
+        buddy.free(again);
+    }
+
+    #[test]
+    fn test_realloc() {
+        let mut buddy = buddy();
+
+        let small = buddy.malloc(16);
+        unsafe { *small = 42 };
+        // shrinking keeps the block
+        let same = buddy.realloc(small, 8);
+        assert_eq!(small, same);
+
+        let grown = buddy.realloc(same, 1000);
+        assert_eq!(42, unsafe { *grown });
+        buddy.free(grown);
+    }
+
+    #[test]
+    fn test_calloc_zeroes() {
+        let mut buddy = buddy();
+        let dirty = buddy.malloc(200);
+        unsafe { (dirty as *mut u8).write_bytes(0xff, 200) };
+        buddy.free(dirty);
+
+        let zeroed = buddy.calloc(25, 8);
+        assert!((0..25).all(|i| unsafe { *zeroed.add(i) } == 0));
+    }
+
+    #[test]
+    fn test_exhausted_slice() {
+        let mut memory = [0u8; 384];
+        let mut buddy = Buddy::new(Slice::new(&mut memory));
+
+        let first = buddy.malloc(200);
+        assert!(!first.is_null());
+        // doubling needs another 256 bytes
+        assert!(buddy.malloc(200).is_null());
+        buddy.free(first);
+        assert_eq!(first, buddy.malloc(200));
+    }
+}
@@ -0,0 +1,163 @@
+use core::fmt;
+use core::mem::size_of;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::types::{Block, Data};
+use super::{arena, buddy, tcache, use_buddy};
+
+/// What `free` does with a pointer that fails its checks, see `set_free_check`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FreeCheck {
+    /// Print the diagnostic to stderr and abort the process, or panic without std.
+    Abort,
+    /// Print the diagnostic to stderr, without std only count it, and leave the pointer alone.
+    Log,
+    /// Leave the pointer alone.
+    Ignore,
+}
+
+const CHECK_ABORT: usize = 0;
+const CHECK_LOG: usize = 1;
+const CHECK_IGNORE: usize = 2;
+
+static FREE_CHECK: AtomicUsize = AtomicUsize::new(CHECK_ABORT);
+static INVALID_FREES: AtomicUsize = AtomicUsize::new(0);
+
+/// Why a pointer cannot be freed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FreeError {
+    /// Not word aligned, or not at the start of a slot.
+    Misaligned,
+    /// Outside the heap, and not a mapping of its own either.
+    OutOfBounds,
+    /// The checksum of the block header doesn't match, the pointer is not at the start of an
+    /// allocation or the header was overwritten.
+    Corrupt,
+    /// The block is free already, or cached by this thread.
+    DoubleFree,
+}
+
+/// A pointer `free` refused.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InvalidFree {
+    pub ptr: usize,
+    pub error: FreeError,
+}
+
+impl fmt::Display for FreeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FreeError::Misaligned => "misaligned pointer",
+            FreeError::OutOfBounds => "pointer outside the heap",
+            FreeError::Corrupt => "corrupt block header",
+            FreeError::DoubleFree => "double free",
+        })
+    }
+}
+
+impl fmt::Display for InvalidFree {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "free(): {} at {:#x}", self.error, self.ptr)
+    }
+}
+
+/// Choose what `free` does with an invalid pointer, `FreeCheck::Abort` by default.
+pub fn set_free_check(check: FreeCheck) {
+    let state = match check {
+        FreeCheck::Abort => CHECK_ABORT,
+        FreeCheck::Log => CHECK_LOG,
+        FreeCheck::Ignore => CHECK_IGNORE,
+    };
+    FREE_CHECK.store(state, Ordering::Relaxed);
+}
+
+/// Invalid pointers passed to `free` and `realloc` so far.
+pub fn invalid_frees() -> usize {
+    INVALID_FREES.load(Ordering::Relaxed)
+}
+
+/// Find the block of `ptr`, making sure it was handed out by the heap and is in use.
+///
+/// A pointer outside every arena has its header read to see whether it is a mapping of its own,
+/// like glibc this faults on a pointer to unmapped memory. A block that was merged into another
+/// one since it was freed may go unnoticed.
+pub fn check_free(ptr: *mut usize) -> Result<Block, FreeError> {
+    if !(ptr as usize).is_multiple_of(size_of::<usize>()) {
+        return Err(FreeError::Misaligned);
+    }
+
+    let block = Data(ptr).get_block();
+    let in_heap = arena::contains(&block) || use_buddy() && buddy::global().lock().contains(&block);
+    if !(in_heap || (block.header().is_intact() && block.header().is_mmapped())) {
+        return Err(FreeError::OutOfBounds);
+    }
+    if !block.header().is_intact() {
+        return Err(FreeError::Corrupt);
+    }
+    if block.is_free() || tcache::holds(&block) {
+        return Err(FreeError::DoubleFree);
+    }
+    Ok(block)
+}
+
+/// Respond to an invalid `ptr` as set by `set_free_check`.
+pub fn report(ptr: *mut usize, error: FreeError) {
+    INVALID_FREES.fetch_add(1, Ordering::Relaxed);
+    let invalid = InvalidFree {
+        ptr: ptr as usize,
+        error,
+    };
+    match FREE_CHECK.load(Ordering::Relaxed) {
+        CHECK_ABORT => abort(invalid),
+        CHECK_LOG => log(invalid),
+        _ => {}
+    }
+}
+
+#[cfg(feature = "std")]
+fn abort(invalid: InvalidFree) {
+    std::eprintln!("malloc_rs: {}", invalid);
+    std::process::abort();
+}
+
+/// Without std the panic handler decides.
+#[cfg(not(feature = "std"))]
+fn abort(invalid: InvalidFree) {
+    panic!("malloc_rs: {}", invalid);
+}
+
+#[cfg(feature = "std")]
+fn log(invalid: InvalidFree) {
+    std::eprintln!("malloc_rs: {}", invalid);
+}
+
+#[cfg(not(feature = "std"))]
+fn log(_invalid: InvalidFree) {}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_free, FreeError};
+    use crate::malloc::tests::MUTEX;
+    use crate::malloc::types::Data;
+    use crate::malloc::{free, malloc};
+
+    #[test]
+    fn test_check_free() {
+        let _lock = MUTEX.lock().unwrap();
+        let ptr = malloc(64);
+        assert!(check_free(ptr) == Ok(Data(ptr).get_block()));
+        let misaligned = (ptr as usize + 4) as *mut usize;
+        assert_eq!(Some(FreeError::Misaligned), check_free(misaligned).err());
+        // inside the allocation, where the header would be is data
+        unsafe { ptr.add(1).write_bytes(0, 3) };
+        let inside = unsafe { ptr.add(4) };
+        assert_eq!(Some(FreeError::Corrupt), check_free(inside).err());
+
+        let outside = [0usize; 4];
+        let outside = &outside[3] as *const usize as *mut usize;
+        assert_eq!(Some(FreeError::OutOfBounds), check_free(outside).err());
+
+        free(ptr);
+        assert_eq!(Some(FreeError::DoubleFree), check_free(ptr).err());
+    }
+}
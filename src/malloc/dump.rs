@@ -0,0 +1,143 @@
+use std::io::{self, Write};
+
+use super::arena;
+use super::heap::Heap;
+use super::source::MemorySource;
+use super::types::Block;
+
+/// How `dump_heap` writes the blocks, one line per block.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DumpFormat {
+    /// One JSON object per line, e.g.
+    /// `{"arena":0,"address":93825001644032,"offset":0,"data_size":0,"total_size":24,"free":false}`.
+    JsonLines,
+    /// Comma separated values with the same fields, after a header line.
+    Csv,
+}
+
+const CSV_HEADER: &str = "arena,address,offset,data_size,total_size,free";
+
+/// Write every block of `heap`, from the root block on. The offset is counted from the root.
+fn dump<S: MemorySource, W: Write>(
+    heap: &Heap<S>,
+    arena: usize,
+    writer: &mut W,
+    format: DumpFormat,
+) -> io::Result<()> {
+    let root = heap.root();
+    if root.is_null() {
+        return Ok(());
+    }
+
+    for block in Some(root).into_iter().chain(heap.blocks()) {
+        write_block(&block, root.0 as usize, arena, writer, format)?;
+    }
+    Ok(())
+}
+
+fn write_block<W: Write>(
+    block: &Block,
+    start: usize,
+    arena: usize,
+    writer: &mut W,
+    format: DumpFormat,
+) -> io::Result<()> {
+    let address = block.0 as usize;
+    let offset = address - start;
+    let data_size = block.get_data_size();
+    let total_size = block.get_total_size();
+    let free = block.is_free();
+    match format {
+        DumpFormat::JsonLines => writeln!(
+            writer,
+            r#"{{"arena":{},"address":{},"offset":{},"data_size":{},"total_size":{},"free":{}}}"#,
+            arena, address, offset, data_size, total_size, free
+        ),
+        DumpFormat::Csv => writeln!(
+            writer,
+            "{},{},{},{},{},{}",
+            arena, address, offset, data_size, total_size, free
+        ),
+    }
+}
+
+/// Write a snapshot of every arena's block list to `writer`, see `DumpFormat`. Arenas without a
+/// heap are skipped.
+///
+/// An arena stays locked while its blocks are written. If `Malloc` is the global allocator,
+/// `writer` must not allocate, e.g. write to a `File` or a `Vec` with enough capacity.
+pub fn dump_heap<W: Write>(mut writer: W, format: DumpFormat) -> io::Result<()> {
+    if format == DumpFormat::Csv {
+        writeln!(writer, "{}", CSV_HEADER)?;
+    }
+    for (index, arena) in arena::all().iter().enumerate() {
+        dump(&arena.lock(), index, &mut writer, format)?;
+    }
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{dump, dump_heap, DumpFormat};
+    use crate::malloc::heap::Heap;
+    use crate::malloc::source::MmapRegion;
+    use crate::malloc::tests::MUTEX;
+    use crate::malloc::types::Data;
+    use crate::malloc::{free, malloc};
+
+    fn lines(heap: &Heap<MmapRegion>, format: DumpFormat) -> Vec<String> {
+        let mut out = Vec::new();
+        dump(heap, 0, &mut out, format).unwrap();
+        String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(String::from)
+            .collect()
+    }
+
+    #[test]
+    fn test_dump() {
+        let mut heap = Heap::new(MmapRegion::new(1 << 20).unwrap());
+        assert!(lines(&heap, DumpFormat::JsonLines).is_empty());
+
+        let first = heap.malloc(32);
+        let _guard = heap.malloc(16);
+        heap.free(first);
+        let root = heap.root().0 as usize;
+
+        let csv = lines(&heap, DumpFormat::Csv);
+        assert_eq!(
+            vec![
+                format!("0,{},0,0,24,false", root),
+                format!("0,{},24,32,56,true", root + 24),
+                format!("0,{},80,16,40,false", root + 80),
+            ],
+            csv
+        );
+
+        let json = lines(&heap, DumpFormat::JsonLines);
+        assert_eq!(3, json.len());
+        assert_eq!(
+            format!(
+                r#"{{"arena":0,"address":{},"offset":24,"data_size":32,"total_size":56,"free":true}}"#,
+                root + 24
+            ),
+            json[1]
+        );
+    }
+
+    #[test]
+    fn test_dump_heap() {
+        let _lock = MUTEX.lock().unwrap();
+        let tmp = malloc(100);
+        let mut out = Vec::with_capacity(1 << 16);
+        dump_heap(&mut out, DumpFormat::Csv).unwrap();
+        free(tmp);
+
+        let out = String::from_utf8(out).unwrap();
+        let mut lines = out.lines();
+        assert_eq!(Some(super::CSV_HEADER), lines.next());
+        let block = Data(tmp).get_block().0 as usize;
+        assert!(lines.any(|line| line.split(',').nth(1) == Some(&block.to_string())));
+    }
+}
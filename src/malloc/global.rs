@@ -0,0 +1,107 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::cmp;
+use core::mem::size_of;
+use core::ptr;
+
+use super::{calloc, free, malloc, memalign, realloc};
+
+/// Every pointer returned by `malloc` is aligned to a machine word.
+const MIN_ALIGN: usize = size_of::<usize>();
+
+/// Zero-sized handle to the process-wide heap, so the crate can be installed with
+/// `#[global_allocator] static GLOBAL: Malloc = Malloc;`.
+pub struct Malloc;
+
+unsafe impl GlobalAlloc for Malloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if layout.align() <= MIN_ALIGN {
+            malloc(layout.size()) as *mut u8
+        } else {
+            memalign(layout.align(), layout.size()) as *mut u8
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
+        free(ptr as *mut usize);
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        if layout.align() <= MIN_ALIGN {
+            return calloc(1, layout.size()) as *mut u8;
+        }
+
+        let ptr = self.alloc(layout);
+        if !ptr.is_null() {
+            ptr::write_bytes(ptr, 0, layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if layout.align() <= MIN_ALIGN {
+            return realloc(ptr as *mut usize, new_size) as *mut u8;
+        }
+
+        // `realloc` may move the data to a block that is only word aligned
+        let new = memalign(layout.align(), new_size) as *mut u8;
+        if !new.is_null() {
+            ptr::copy_nonoverlapping(ptr, new, cmp::min(layout.size(), new_size));
+            free(ptr as *mut usize);
+        }
+        new
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::alloc::{GlobalAlloc, Layout};
+
+    use super::Malloc;
+    use crate::malloc::tests::MUTEX;
+
+    #[test]
+    fn test_alloc_honours_align() {
+        let _lock = MUTEX.lock().unwrap();
+        for align in [1, 8, 16, 64, 4096] {
+            let layout = Layout::from_size_align(100, align).unwrap();
+            let ptr = unsafe { Malloc.alloc(layout) };
+            assert_eq!(0, ptr as usize % align);
+            unsafe {
+                ptr.write_bytes(0xab, 100);
+                Malloc.dealloc(ptr, layout);
+            }
+        }
+    }
+
+    #[test]
+    fn test_alloc_zeroed() {
+        let _lock = MUTEX.lock().unwrap();
+        let layout = Layout::from_size_align(64, 32).unwrap();
+        unsafe {
+            let dirty = Malloc.alloc(layout);
+            dirty.write_bytes(0xff, 64);
+            Malloc.dealloc(dirty, layout);
+
+            let ptr = Malloc.alloc_zeroed(layout);
+            assert!((0..64).all(|i| *ptr.add(i) == 0));
+            Malloc.dealloc(ptr, layout);
+        }
+    }
+
+    #[test]
+    fn test_realloc_keeps_contents() {
+        let _lock = MUTEX.lock().unwrap();
+        let layout = Layout::from_size_align(16, 64).unwrap();
+        unsafe {
+            let ptr = Malloc.alloc(layout);
+            for i in 0..16 {
+                *ptr.add(i) = i as u8;
+            }
+
+            let grown = Malloc.realloc(ptr, layout, 1000);
+            assert_eq!(0, grown as usize % 64);
+            assert!((0..16).all(|i| *grown.add(i) == i as u8));
+            Malloc.dealloc(grown, Layout::from_size_align(1000, 64).unwrap());
+        }
+    }
+}
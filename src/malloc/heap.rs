@@ -0,0 +1,786 @@
+use core::mem::size_of;
+use core::ptr;
+
+use super::bins::Bins;
+use super::mmap::page_align;
+use super::source::MemorySource;
+use super::stats::Counters;
+use super::types::{Block, Data, Header, MIN_DATA_SIZE};
+use super::{data_size, good_fit_slack, search_strategy, SearchStrategy, DEFAULT_TRIM_THRESHOLD};
+
+/// A heap that owns its block list and free lists and takes its memory from `S`.
+///
+/// `malloc` and friends are wrappers around a set of these, see `Arena`. A `Heap` of its own
+/// is single threaded and has no thread cache or mmap path, put it behind a lock to share it.
+///
+/// ```
+/// use malloc_rs::malloc::{Heap, MmapRegion};
+///
+/// let mut heap = Heap::new(MmapRegion::new(1 << 20).unwrap());
+/// let ptr = heap.malloc(64);
+/// heap.free(ptr);
+/// ```
+pub struct Heap<S: MemorySource> {
+    source: S,
+    root: Block,
+    /// The block that ends at the top of the source, new blocks are appended after it.
+    last: Block,
+    bins: Bins,
+    trim_threshold: usize,
+    counters: Counters,
+    /// Blocks of a non-main arena carry a flag so `free` can find their arena.
+    non_main_arena: bool,
+}
+
+// blocks are only reachable through the heap
+unsafe impl<S: MemorySource + Send> Send for Heap<S> {}
+
+impl<S: MemorySource> Heap<S> {
+    /// Create an empty heap, no memory is taken from `source` until the first allocation.
+    pub const fn new(source: S) -> Heap<S> {
+        Heap::with_arena_flag(source, false)
+    }
+
+    pub(super) const fn with_arena_flag(source: S, non_main_arena: bool) -> Heap<S> {
+        Heap {
+            source,
+            root: Block::null(),
+            last: Block::null(),
+            bins: Bins::new(),
+            trim_threshold: DEFAULT_TRIM_THRESHOLD,
+            counters: Counters::new(),
+            non_main_arena,
+        }
+    }
+
+    pub fn source(&self) -> &S {
+        &self.source
+    }
+
+    pub(super) fn source_mut(&mut self) -> &mut S {
+        &mut self.source
+    }
+
+    pub(super) fn is_initialized(&self) -> bool {
+        !self.root.is_null()
+    }
+
+    /// The sentinel block every walk over the heap starts from, null before the first allocation.
+    pub(super) fn root(&self) -> Block {
+        self.root
+    }
+
+    pub(super) fn last(&self) -> Block {
+        self.last
+    }
+
+    /// Blocks after the root, in address order.
+    pub(super) fn blocks(&self) -> impl Iterator<Item = Block> {
+        let mut block = self.root;
+        core::iter::from_fn(move || {
+            if block.is_null() || !block.has_next() {
+                return None;
+            }
+            block = *block.next();
+            Some(block)
+        })
+    }
+
+    pub(super) fn counters(&self) -> &Counters {
+        &self.counters
+    }
+
+    /// Give memory back to the source in `free` once the free last block spans at least
+    /// `threshold` bytes. `usize::MAX` disables automatic trimming.
+    pub fn set_trim_threshold(&mut self, threshold: usize) {
+        self.trim_threshold = threshold;
+    }
+
+    /// Returns null if the source is exhausted.
+    pub fn malloc(&mut self, size: usize) -> *mut usize {
+        assert!(size > 0);
+        self.allocate(size, search_strategy()).0
+    }
+
+    /// Allocate zero-initialized memory for `count` elements of `size` bytes each.
+    /// Returns null if `count * size` overflows or is zero, or if the source is exhausted.
+    pub fn calloc(&mut self, count: usize, size: usize) -> *mut usize {
+        let total_size = match count.checked_mul(size) {
+            None | Some(0) => return ptr::null_mut(),
+            Some(total_size) => total_size,
+        };
+
+        let (res, fresh) = self.allocate(total_size, search_strategy());
+        if !res.is_null() && !fresh {
+            unsafe { ptr::write_bytes(res as *mut u8, 0, total_size) };
+        }
+        res
+    }
+
+    /// Allocate `size` bytes at a multiple of `alignment`, which must be a power of two.
+    /// Returns null if `size` is zero, `alignment` is invalid or the source is exhausted.
+    pub fn memalign(&mut self, alignment: usize, size: usize) -> *mut usize {
+        if size == 0 || !alignment.is_power_of_two() {
+            return ptr::null_mut();
+        }
+        self.allocate_aligned(alignment, size, search_strategy())
+    }
+
+    /// Resize the allocation at `ptr` to `size` bytes, in place whenever possible.
+    /// A null `ptr` behaves like `malloc`, a zero `size` frees `ptr`, if any, and returns null.
+    /// Returns null, leaving `ptr` untouched, if the source is exhausted.
+    pub fn realloc(&mut self, ptr: *mut usize, size: usize) -> *mut usize {
+        if size == 0 {
+            if !ptr.is_null() {
+                self.free(ptr);
+            }
+            return ptr::null_mut();
+        }
+        if ptr.is_null() {
+            return self.malloc(size);
+        }
+
+        let block = Data(ptr).get_block();
+        if self.resize_in_place(&block, data_size(size)) {
+            return ptr;
+        }
+
+        let new = self.malloc(size);
+        if !new.is_null() {
+            unsafe {
+                ptr::copy_nonoverlapping(
+                    ptr as *const u8,
+                    new as *mut u8,
+                    block.get_data_size().min(size),
+                )
+            };
+            self.free(ptr);
+        }
+        new
+    }
+
+    pub fn free(&mut self, ptr: *mut usize) {
+        assert!(ptr as usize != 0);
+        let block = Data(ptr).get_block();
+        self.release(&block, self.trim_threshold);
+    }
+
+    /// Place the sentinel root block at the start of the source.
+    fn init(&mut self) -> bool {
+        let root = self.extend(size_of::<Header>());
+        if root.is_null() {
+            return false;
+        }
+
+        self.root = Block::from_usize(root as usize);
+        self.write_header(&self.root, 0);
+        self.last = self.root;
+        true
+    }
+
+    /// Write a fresh, occupied header carrying the arena flag.
+    fn write_header(&self, block: &Block, data_size: usize) {
+        unsafe { *block.0 = Header::from_usize(data_size) };
+        if self.non_main_arena {
+            block.header().set_non_main_arena();
+        }
+    }
+
+    /// Keep `last` up to date after `block` was split or merged.
+    /// Only `block` or the block split off it can have become the last block.
+    fn track_last(&mut self, block: &Block) {
+        if !block.has_next() {
+            self.last = *block;
+        } else if !block.next().has_next() {
+            self.last = *block.next();
+        }
+    }
+
+    /// Search free lists for a spot of `data_size` bytes or return the last block.
+    /// Caller must check the `bool == true` flag if it found spot, otherwise `Block` is the last block.
+    fn search_free_spot_or_last(
+        &mut self,
+        data_size: usize,
+        search_strategy: SearchStrategy,
+    ) -> (Block, bool) {
+        match search_strategy {
+            SearchStrategy::FirstFit => self.search_first_fit(data_size),
+            SearchStrategy::BestFit => self.search_best_fit(data_size, 0),
+            SearchStrategy::NextFit => self.search_next_fit(data_size),
+            SearchStrategy::WorstFit => self.search_worst_fit(data_size),
+            SearchStrategy::GoodFit => self.search_best_fit(data_size, good_fit_slack()),
+        }
+    }
+
+    /// Returns the first free block that fits, starting from the size class of `data_size`.
+    /// In case no block fits, `bool` is false, and `Block` is the last block.
+    fn search_first_fit(&self, data_size: usize) -> (Block, bool) {
+        match self.bins.first_fit(data_size) {
+            Some(block) => (block, true),
+            None => (self.last, false),
+        }
+    }
+
+    /// Returns the minimum-sized free block that still fits `data_size`, or the first one with
+    /// at most `slack` bytes to spare.
+    fn search_best_fit(&self, data_size: usize, slack: usize) -> (Block, bool) {
+        match self.bins.best_fit(data_size, slack) {
+            Some(block) => (block, true),
+            None => (self.last, false),
+        }
+    }
+
+    /// Returns the first free block that fits after the one allocated last, in the order of
+    /// the free lists, or else the first one before it.
+    fn search_next_fit(&mut self, data_size: usize) -> (Block, bool) {
+        match self.bins.next_fit(data_size) {
+            Some(block) => (block, true),
+            None => (self.last, false),
+        }
+    }
+
+    /// Returns the largest free block if it fits `data_size`.
+    fn search_worst_fit(&self, data_size: usize) -> (Block, bool) {
+        match self.bins.worst_fit(data_size) {
+            Some(block) => (block, true),
+            None => (self.last, false),
+        }
+    }
+
+    /// Find or create a block for `size` bytes and return its data pointer, or null if the
+    /// source is exhausted. The flag is true when the block was freshly taken from a source
+    /// that hands out zeroed memory, instead of reused.
+    pub(super) fn allocate(
+        &mut self,
+        size: usize,
+        search_strategy: SearchStrategy,
+    ) -> (*mut usize, bool) {
+        if !self.is_initialized() && !self.init() {
+            return (ptr::null_mut(), false);
+        }
+
+        let aligned_size = data_size(size);
+        let total_size = aligned_size + Block::get_total_padding();
+
+        let (block, found) = self.search_free_spot_or_last(aligned_size, search_strategy);
+
+        if found {
+            // `block` can be reuse
+            self.bins.remove(&block);
+            let new = self.split(block, aligned_size);
+            self.track_last(&new);
+
+            (new.data().unwrap().0, false)
+        } else {
+            // `block` is the last Block, allocate new memory
+            let current = self.extend(total_size);
+            if current.is_null() {
+                return (ptr::null_mut(), false);
+            }
+
+            let new = Block::from_usize(current as usize);
+            self.write_header(&new, aligned_size);
+            new.header().set_prev(block);
+            block.header().set_next(new);
+            self.last = new;
+
+            (new.data().unwrap().0, self.source.is_zeroed())
+        }
+    }
+
+    /// Over-allocate, then carve the leading slack off as its own free block so the returned
+    /// pointer starts a regular block.
+    pub(super) fn allocate_aligned(
+        &mut self,
+        alignment: usize,
+        size: usize,
+        search_strategy: SearchStrategy,
+    ) -> *mut usize {
+        if alignment <= size_of::<usize>() {
+            return self.allocate(size, search_strategy).0;
+        }
+
+        let aligned_size = data_size(size);
+        let min_block_size = Block::get_total_padding() + MIN_DATA_SIZE;
+        let (res, _) = self.allocate(aligned_size + alignment + min_block_size, search_strategy);
+        if res.is_null() {
+            return res;
+        }
+
+        let mut block = Data(res).get_block();
+        if !(res as usize).is_multiple_of(alignment) {
+            // the slack must be large enough to become a block of its own
+            let mut aligned = (res as usize + alignment - 1) & !(alignment - 1);
+            while aligned - (res as usize) < min_block_size {
+                aligned += alignment;
+            }
+
+            let lead = block;
+            let lead_size = aligned - res as usize;
+            block = Data(aligned as *mut usize).get_block();
+            self.write_header(&block, lead.get_data_size() - lead_size);
+            block.header().set_prev(lead);
+            block.header().set_next(*lead.next());
+            if block.has_next() {
+                block.next().header().set_prev(block);
+            }
+            lead.header().set_next(block);
+            lead.header()
+                .set_size(lead_size - Block::get_total_padding());
+
+            lead.set_free(true);
+            self.coalesce(&lead);
+        }
+
+        self.split(block, aligned_size);
+        self.track_last(&block);
+
+        block.data().unwrap().0
+    }
+
+    /// Put a block back into the heap: coalesce it, and trim the heap if it ends up last with
+    /// at least `trim_threshold` bytes.
+    pub(super) fn release(&mut self, block: &Block, trim_threshold: usize) {
+        block.set_free(true);
+        let block = self.coalesce(block);
+        self.track_last(&block);
+
+        if !block.has_next() && block.get_total_size() >= trim_threshold {
+            self.trim(0);
+        }
+    }
+
+    /// Give the memory of the free last block back to the source, keeping `pad` bytes of it.
+    /// Returns true if the heap shrank.
+    ///
+    /// The new top is a page boundary, which keeps memory handed out above it later zeroed by
+    /// the kernel.
+    pub fn trim(&mut self, pad: usize) -> bool {
+        let last = self.last;
+        if last.is_null() || !last.is_free() {
+            return false;
+        }
+
+        let data_start = last.0 as usize + Block::get_total_padding();
+        let new_top = page_align(data_start + pad.max(MIN_DATA_SIZE));
+        if new_top >= self.source.top() || !self.source.shrink(new_top) {
+            return false;
+        }
+
+        self.bins.remove(&last);
+        last.header().set_size(new_top - data_start);
+        self.bins.insert(&last);
+        self.counters.trims += 1;
+        true
+    }
+
+    /// Shrink `block` by splitting off its tail, or grow it by absorbing the free next block and/or
+    /// extending the source when it is the last block.
+    /// Returns false, leaving `block` untouched, if neither is possible.
+    pub(super) fn resize_in_place(&mut self, block: &Block, data_size: usize) -> bool {
+        let mut available = block.get_data_size();
+        let mut is_last = !block.has_next();
+
+        let next_is_free = block.has_next() && block.next().is_free();
+        if next_is_free {
+            available += block.next().get_total_size();
+            is_last = !block.next().has_next();
+        }
+
+        if available < data_size {
+            if !is_last || self.extend(data_size - available).is_null() {
+                return false;
+            }
+            available = data_size;
+        }
+
+        if next_is_free {
+            self.bins.remove(block.next());
+            block.merge_next();
+            self.counters.coalesces += 1;
+        }
+        block.header().set_size(available);
+
+        self.split(*block, data_size);
+        self.track_last(block);
+        true
+    }
+
+    /// Take `increment` more bytes from the source, see `MemorySource::extend`.
+    fn extend(&mut self, increment: usize) -> *mut u8 {
+        let res = self.source.extend(increment);
+        if !res.is_null() {
+            self.counters.grows += 1;
+            // the root is the first memory extended
+            let start = if self.root.is_null() {
+                res as usize
+            } else {
+                self.root.0 as usize
+            };
+            let size = res as usize + increment - start;
+            self.counters.peak_bytes = self.counters.peak_bytes.max(size);
+        }
+        res
+    }
+
+    /// Occupy `block` and split off its tail, see `Block::split`.
+    fn split(&mut self, block: Block, data_size: usize) -> Block {
+        let before = block.get_data_size();
+        if block.split(data_size, &mut self.bins) {
+            self.counters.coalesces += 1;
+        }
+        if block.get_data_size() < before {
+            self.counters.splits += 1;
+        }
+        block
+    }
+
+    /// Merge a freed block with its free neighbours, see `Block::coalesce`.
+    fn coalesce(&mut self, block: &Block) -> Block {
+        let next_free = block.has_next() && block.next().is_free();
+        let prev_free = block.has_prev() && block.prev().is_free();
+        self.counters.coalesces += next_free as usize + prev_free as usize;
+        block.coalesce(&mut self.bins)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ptr;
+    use std::thread;
+
+    use super::{Heap, SearchStrategy};
+    use crate::malloc::data_size;
+    use crate::malloc::mmap::page_align;
+    use crate::malloc::source::{MemorySource, MmapRegion, Slice};
+    use crate::malloc::types::Block;
+
+    /// Every test gets a heap of its own, so they can run in parallel.
+    fn heap() -> Heap<MmapRegion> {
+        Heap::new(MmapRegion::new(64 << 20).unwrap())
+    }
+
+    #[test]
+    fn test_malloc() {
+        let mut heap = heap();
+        let total_size = |data| -> usize { Block::get_total_padding() + data_size(data) };
+
+        // the first allocation also places the root block
+        let initial_top = heap.source().top() + Block::get_total_padding();
+        println!("initial_top {:?}", initial_top);
+
+        let requests = [3, 4, 8, 13, 28, 321];
+        let mut counter_size = 0;
+        for request in requests {
+            heap.malloc(request);
+            counter_size += total_size(request);
+        }
+
+        let final_top = heap.source().top();
+        println!("final_top {:?}", final_top);
+
+        assert_eq!(initial_top + counter_size, final_top);
+    }
+
+    #[test]
+    fn test_free() {
+        let mut heap = heap();
+        heap.set_trim_threshold(usize::MAX);
+        let total_size = |data| -> usize { Block::get_total_padding() + data_size(data) };
+
+        // the first allocation also places the root block
+        let initial_top = heap.source().top() + Block::get_total_padding();
+        println!("initial_top {:?}", initial_top);
+
+        let mut tmp = heap.malloc(18);
+        heap.free(tmp);
+        tmp = heap.malloc(17);
+        heap.free(tmp);
+        tmp = heap.malloc(18);
+        heap.free(tmp);
+        tmp = heap.malloc(24);
+        heap.free(tmp);
+        let tmp1 = heap.malloc(1048576);
+        let tmp2 = heap.malloc(1048576);
+        let tmp3 = heap.malloc(1048576);
+        heap.free(tmp1);
+        heap.free(tmp2);
+        heap.free(tmp3);
+        tmp = heap.malloc(3145728);
+        heap.free(tmp);
+
+        let final_top = heap.source().top();
+        println!("final_top {:?}", final_top);
+
+        let max = 3 * total_size(1048576) + total_size(24);
+        assert_eq!(initial_top + max, final_top);
+    }
+
+    #[test]
+    fn test_realloc_shrink_in_place() {
+        let mut heap = heap();
+        let padding = Block::get_total_padding();
+
+        let tmp = heap.malloc(256);
+        let _guard = heap.malloc(8);
+        assert_eq!(tmp, heap.realloc(tmp, 64));
+
+        // the split off tail is reused by the next allocation that fits
+        let tail = heap.malloc(100);
+        assert_eq!(tmp as usize + 64 + padding, tail as usize);
+    }
+
+    #[test]
+    fn test_realloc_grow_into_next() {
+        let mut heap = heap();
+
+        let tmp = heap.malloc(64);
+        let next = heap.malloc(256);
+        let _guard = heap.malloc(8);
+        heap.free(next);
+
+        let initial_top = heap.source().top();
+        assert_eq!(tmp, heap.realloc(tmp, 200));
+        assert_eq!(initial_top, heap.source().top());
+    }
+
+    #[test]
+    fn test_realloc_grow_last_extends_top() {
+        let mut heap = heap();
+
+        let tmp = heap.malloc(64);
+        let initial_top = heap.source().top();
+        assert_eq!(tmp, heap.realloc(tmp, 4096));
+
+        let final_top = heap.source().top();
+        assert_eq!(initial_top + 4096 - 64, final_top);
+    }
+
+    #[test]
+    fn test_realloc_moves() {
+        let mut heap = heap();
+
+        let tmp = heap.malloc(16);
+        let _guard = heap.malloc(8);
+        unsafe {
+            *tmp = 42;
+            *tmp.add(1) = 43;
+        }
+
+        let moved = heap.realloc(tmp, 1024);
+        assert_ne!(tmp, moved);
+        assert_eq!(unsafe { (*moved, *moved.add(1)) }, (42, 43));
+        heap.free(moved);
+    }
+
+    #[test]
+    fn test_realloc_zero_size() {
+        let mut heap = heap();
+
+        assert!(heap.realloc(ptr::null_mut(), 0).is_null());
+        let tmp = heap.malloc(64);
+        let _guard = heap.malloc(8);
+        assert!(heap.realloc(tmp, 0).is_null());
+        assert_eq!(tmp, heap.malloc(64));
+    }
+
+    #[test]
+    fn test_calloc_zeroes_reused_block() {
+        let mut heap = heap();
+
+        let tmp = heap.malloc(64);
+        let _guard = heap.malloc(8);
+        unsafe { (tmp as *mut u8).write_bytes(0xff, 64) };
+        heap.free(tmp);
+
+        let zeroed = heap.calloc(8, 8);
+        assert_eq!(tmp, zeroed);
+        assert!((0..8).all(|i| unsafe { *zeroed.add(i) } == 0));
+    }
+
+    #[test]
+    fn test_calloc_fresh_block() {
+        let mut heap = heap();
+
+        let initial_top = heap.source().top();
+        let zeroed = heap.calloc(512, 8);
+        assert!(initial_top < heap.source().top());
+        assert!((0..512).all(|i| unsafe { *zeroed.add(i) } == 0));
+    }
+
+    #[test]
+    fn test_free_list_reuse() {
+        let mut heap = heap();
+
+        let small = heap.malloc(32);
+        let _guard1 = heap.malloc(8);
+        let large = heap.malloc(4096);
+        let _guard2 = heap.malloc(8);
+        heap.free(small);
+        heap.free(large);
+
+        // the small block's size class is skipped, the large one is split
+        let initial_top = heap.source().top();
+        assert_eq!(large, heap.malloc(1024));
+        assert_eq!(small, heap.malloc(24));
+        assert_eq!(initial_top, heap.source().top());
+    }
+
+    #[test]
+    fn test_next_fit() {
+        let mut heap = heap();
+        let next_fit = |heap: &mut Heap<MmapRegion>| heap.allocate(64, SearchStrategy::NextFit).0;
+
+        let first = heap.malloc(64);
+        let _guard1 = heap.malloc(8);
+        let second = heap.malloc(64);
+        let _guard2 = heap.malloc(8);
+        let third = heap.malloc(64);
+        let _guard3 = heap.malloc(8);
+        heap.free(first);
+        heap.free(second);
+        heap.free(third);
+
+        // the free list is third, second, first
+        assert_eq!(third, next_fit(&mut heap));
+        // resume after `third`, where first fit would take the most recently freed block
+        heap.free(third);
+        assert_eq!(second, next_fit(&mut heap));
+        assert_eq!(first, next_fit(&mut heap));
+        // nothing is left past the rover, wrap around
+        assert_eq!(third, next_fit(&mut heap));
+    }
+
+    #[test]
+    fn test_worst_fit() {
+        let mut heap = heap();
+
+        let small = heap.malloc(64);
+        let _guard1 = heap.malloc(8);
+        let large = heap.malloc(4096);
+        let guard2 = heap.malloc(8);
+        heap.free(large);
+        heap.free(small);
+
+        assert_eq!(large, heap.allocate(32, SearchStrategy::WorstFit).0);
+        // the rest of `large` is the largest block left and too small
+        assert!(heap.allocate(4096, SearchStrategy::WorstFit).0 > guard2);
+    }
+
+    #[test]
+    fn test_good_fit() {
+        let mut heap = heap();
+
+        let tight = heap.malloc(72);
+        let _guard1 = heap.malloc(8);
+        let loose = heap.malloc(120);
+        let _guard2 = heap.malloc(8);
+        heap.free(tight);
+        heap.free(loose);
+
+        // `loose` heads the free list and is within the default slack
+        assert_eq!(loose, heap.allocate(64, SearchStrategy::GoodFit).0);
+        heap.free(loose);
+        assert_eq!(tight, heap.allocate(64, SearchStrategy::BestFit).0);
+    }
+
+    #[test]
+    fn test_memalign() {
+        let mut heap = heap();
+
+        for alignment in [8, 16, 64, 4096] {
+            let tmp = heap.memalign(alignment, 100);
+            assert_eq!(0, tmp as usize % alignment);
+            unsafe { (tmp as *mut u8).write_bytes(0xab, 100) };
+            heap.free(tmp);
+        }
+        assert!(heap.memalign(64, 0).is_null());
+    }
+
+    #[test]
+    fn test_memalign_frees_leading_slack() {
+        let mut heap = heap();
+
+        let _guard1 = heap.malloc(8);
+        let aligned = heap.memalign(4096, 64);
+        let _guard2 = heap.malloc(8);
+
+        // the slack in front of `aligned` is a free block of its own
+        let initial_top = heap.source().top();
+        let tmp = heap.malloc(1024);
+        assert!((tmp as usize) < aligned as usize);
+        assert_eq!(initial_top, heap.source().top());
+        heap.free(aligned);
+    }
+
+    #[test]
+    fn test_trim_on_free() {
+        let mut heap = heap();
+
+        let _guard = heap.malloc(8);
+        let large = heap.malloc(1048576);
+        assert!(heap.source().top() > large as usize + 1048576 - 1);
+
+        heap.free(large);
+        let final_top = heap.source().top();
+        assert_eq!(page_align(large as usize + 16), final_top);
+    }
+
+    #[test]
+    fn test_trim() {
+        let mut heap = heap();
+        heap.set_trim_threshold(usize::MAX);
+
+        let _guard = heap.malloc(8);
+        let tmp = heap.malloc(65536);
+        heap.free(tmp);
+        let initial_top = heap.source().top();
+        assert!(initial_top >= tmp as usize + 65536);
+
+        assert!(heap.trim(8192));
+        let final_top = heap.source().top();
+        assert_eq!(page_align(tmp as usize + 8192), final_top);
+        assert!(!heap.trim(8192));
+
+        // the trimmed block is still usable
+        assert_eq!(tmp, heap.malloc(8192));
+    }
+
+    #[test]
+    fn test_slice() {
+        let mut memory = [0xffu8; 1024];
+        let mut heap = Heap::new(Slice::new(&mut memory));
+
+        let zeroed = heap.calloc(8, 8);
+        assert!((0..8).all(|i| unsafe { *zeroed.add(i) } == 0));
+        assert!(heap.malloc(1024).is_null());
+        heap.free(zeroed);
+        assert_eq!(zeroed, heap.malloc(64));
+    }
+
+    #[test]
+    fn test_heaps_in_threads() {
+        let handles: Vec<_> =
+            (0..4)
+                .map(|t| {
+                    thread::spawn(move || {
+                        let mut heap = heap();
+                        let ptrs: Vec<_> = (1..100).map(|i| heap.malloc(i * 8)).collect();
+                        for (i, ptr) in ptrs.iter().enumerate() {
+                            unsafe { (*ptr as *mut u8).write_bytes(t, (i + 1) * 8) };
+                        }
+                        for (i, ptr) in ptrs.iter().enumerate() {
+                            assert!(
+                                (0..(i + 1) * 8).all(|k| unsafe { *(*ptr as *mut u8).add(k) } == t)
+                            );
+                            heap.free(*ptr);
+                        }
+                    })
+                })
+                .collect();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    }
+}
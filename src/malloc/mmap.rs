@@ -0,0 +1,129 @@
+use core::mem::size_of;
+use core::ptr;
+
+use super::syscalls::{
+    syscall2, syscall3, syscall4, syscall6, Errno, MADVISE, MMAP, MREMAP, MUNMAP,
+};
+use super::types::{Block, Data, Header};
+
+pub const PAGE_SIZE: usize = 4096;
+
+const PROT_READ: usize = 0x1;
+const PROT_WRITE: usize = 0x2;
+const MAP_PRIVATE: usize = 0x02;
+const MAP_ANONYMOUS: usize = 0x20;
+const MREMAP_MAYMOVE: usize = 0x1;
+const MADV_DONTNEED: usize = 4;
+
+/// Round `size` up to a multiple of the page size.
+pub fn page_align(size: usize) -> usize {
+    (size + (PAGE_SIZE - 1)) & !(PAGE_SIZE - 1)
+}
+
+/// Map `len` bytes of zeroed, private anonymous memory.
+pub unsafe fn mmap(len: usize) -> Result<usize, Errno> {
+    // fd is -1 for anonymous mappings
+    syscall6(
+        MMAP,
+        0,
+        len,
+        PROT_READ | PROT_WRITE,
+        MAP_PRIVATE | MAP_ANONYMOUS,
+        usize::MAX,
+        0,
+    )
+}
+
+/// Map `len` bytes starting at a multiple of `alignment`, a power of two, by mapping more and
+/// unmapping the excess around the aligned part.
+pub unsafe fn mmap_aligned(len: usize, alignment: usize) -> Result<usize, Errno> {
+    let alignment = alignment.max(PAGE_SIZE);
+    let base = mmap(len + alignment)?;
+    let start = (base + alignment - 1) & !(alignment - 1);
+    if start > base {
+        let _ = munmap(base, start - base);
+    }
+    let _ = munmap(start + len, base + alignment - start);
+    Ok(start)
+}
+
+pub unsafe fn munmap(addr: usize, len: usize) -> Result<usize, Errno> {
+    syscall2(MUNMAP, addr, len)
+}
+
+/// Drop the pages of `len` bytes at `addr`, the next access finds them zeroed.
+pub unsafe fn discard(addr: usize, len: usize) -> Result<usize, Errno> {
+    syscall3(MADVISE, addr, len, MADV_DONTNEED)
+}
+
+unsafe fn mremap(addr: usize, old_len: usize, new_len: usize) -> Result<usize, Errno> {
+    syscall4(MREMAP, addr, old_len, new_len, MREMAP_MAYMOVE)
+}
+
+/// Give a block of `data_size` bytes, aligned to `alignment`, its own mapping.
+///
+/// The block is marked as mmapped and is not linked into the heap's block list. Its `prev`
+/// points at the start of the mapping, which precedes the block when it had to be aligned.
+/// Returns null if the mapping fails.
+pub fn map(data_size: usize, alignment: usize) -> *mut usize {
+    let padding = Block::get_total_padding();
+    let slack = if alignment > size_of::<usize>() {
+        alignment
+    } else {
+        0
+    };
+
+    let len = page_align(padding + data_size + slack);
+    let base = match unsafe { mmap(len) } {
+        Ok(base) => base,
+        Err(_) => return ptr::null_mut(),
+    };
+
+    let data = (base + padding + (alignment - 1)) & !(alignment - 1);
+    let block = Data(data as *mut usize).get_block();
+    unsafe { *block.0 = Header::from_usize(base + len - data) };
+    block.header().set_mmapped();
+    block.header().set_prev(Block::from_usize(base));
+    data as *mut usize
+}
+
+/// Release the mapping of an mmapped block.
+pub fn unmap(block: &Block) {
+    let base = block.prev().0 as usize;
+    let len = block.0 as usize + block.get_total_size() - base;
+    unsafe { munmap(base, len) }.expect("munmap of an mmapped block failed");
+}
+
+/// Resize the mapping of an mmapped block so it holds at least `data_size` bytes.
+/// The mapping may move, returns the new data pointer or null if remapping fails.
+pub fn remap(block: &Block, data_size: usize) -> *mut usize {
+    let base = block.prev().0 as usize;
+    let offset = block.0 as usize - base;
+    let old_len = offset + block.get_total_size();
+    let new_len = page_align(offset + Block::get_total_padding() + data_size);
+
+    let new_base = match unsafe { mremap(base, old_len, new_len) } {
+        Ok(new_base) => new_base,
+        Err(_) => return ptr::null_mut(),
+    };
+
+    let block = Block::from_usize(new_base + offset);
+    block
+        .header()
+        .set_size(new_len - offset - Block::get_total_padding());
+    block.header().set_prev(Block::from_usize(new_base));
+    block.data().unwrap().0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::page_align;
+
+    #[test]
+    fn test_page_align() {
+        assert_eq!(0, page_align(0));
+        assert_eq!(4096, page_align(1));
+        assert_eq!(4096, page_align(4096));
+        assert_eq!(8192, page_align(4097));
+    }
+}
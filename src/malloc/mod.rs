@@ -1,26 +1,139 @@
-use lazy_static::lazy_static;
+#[cfg(feature = "std")]
 use std::env;
-use std::mem::size_of;
-use std::{sync::Mutex, usize};
+
+use core::mem::size_of;
+use core::ptr;
+use core::str::FromStr;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 use self::{
-    syscalls::{syscall1, BRK},
-    types::{Block, Data, Header},
+    arena::Arena,
+    lock::SpinLockGuard,
+    syscalls::Errno,
+    types::{Block, MIN_DATA_SIZE},
 };
 
+pub use self::arena::{set_arena_max, DEFAULT_ARENA_MAX};
+pub use self::buddy::Buddy;
+pub use self::check::{invalid_frees, set_free_check, FreeCheck, FreeError, InvalidFree};
+#[cfg(feature = "std")]
+pub use self::dump::{dump_heap, DumpFormat};
+pub use self::global::Malloc;
+pub use self::heap::Heap;
+pub use self::slab::{set_slab_max, SlabCache, MAX_SLAB_SIZE};
+pub use self::source::{Brk, MemorySource, MmapRegion, Slice};
+pub use self::stats::{malloc_stats, Histogram, MallocStats, HISTOGRAM_BUCKETS};
+pub use self::tag_heap::TagHeap;
+pub use self::tcache::{set_tcache_count, DEFAULT_TCACHE_COUNT};
+#[cfg(feature = "std")]
+pub use self::trace::{read_trace, start_trace, stop_trace};
+pub use self::trace::{
+    trace_header, TraceOp, TraceRecord, TRACE_GREW, TRACE_HEADER_SIZE, TRACE_MAGIC, TRACE_SPLIT,
+    TRACE_VERSION,
+};
+pub use self::verify::{verify_heap, Corruption, HeapCorruption, HeapReport};
+
+mod arena;
+mod bins;
+mod buddy;
+mod check;
+#[cfg(feature = "std")]
+mod dump;
+mod global;
+mod heap;
+mod lock;
+mod mmap;
+mod slab;
+mod source;
+mod stats;
 mod syscalls;
+mod tag_heap;
+mod tcache;
+mod trace;
 mod types;
+mod verify;
+
+const STRATEGY_UNRESOLVED: usize = 0;
+const STRATEGY_RESOLVING: usize = 1;
+const STRATEGY_FIRST_FIT: usize = 2;
+const STRATEGY_BEST_FIT: usize = 3;
+const STRATEGY_NEXT_FIT: usize = 4;
+const STRATEGY_WORST_FIT: usize = 5;
+const STRATEGY_GOOD_FIT: usize = 6;
+static STRATEGY: AtomicUsize = AtomicUsize::new(STRATEGY_UNRESOLVED);
+
+const BACKEND_BLOCK_LIST: usize = 0;
+const BACKEND_BUDDY: usize = 1;
+const BACKEND_BOUNDARY_TAG: usize = 2;
+static BACKEND: AtomicUsize = AtomicUsize::new(BACKEND_BLOCK_LIST);
+
+/// `GoodFit` takes a block that is at most this many bytes larger than the request.
+pub const DEFAULT_GOOD_FIT_SLACK: usize = 64;
+static GOOD_FIT_SLACK: AtomicUsize = AtomicUsize::new(DEFAULT_GOOD_FIT_SLACK);
+
+/// Requests of at least this many bytes get their own mapping, like glibc's `M_MMAP_THRESHOLD`.
+pub const DEFAULT_MMAP_THRESHOLD: usize = 128 * 1024;
+static MMAP_THRESHOLD: AtomicUsize = AtomicUsize::new(DEFAULT_MMAP_THRESHOLD);
+
+/// `free` gives memory back to the OS once the free last block reaches this many bytes, like
+/// glibc's `M_TRIM_THRESHOLD`.
+pub const DEFAULT_TRIM_THRESHOLD: usize = 128 * 1024;
+static TRIM_THRESHOLD: AtomicUsize = AtomicUsize::new(DEFAULT_TRIM_THRESHOLD);
+
+/// How the heap picks a free block for an allocation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SearchStrategy {
+    /// The first block that fits, the default.
+    FirstFit,
+    /// The smallest block that fits.
+    BestFit,
+    /// The first block that fits after the block of the previous allocation in the free
+    /// lists, wrapping around once.
+    NextFit,
+    /// The largest free block, leaving the largest remainder.
+    WorstFit,
+    /// Like `BestFit`, but stops at the first block with little enough slack, see
+    /// `set_good_fit_slack`.
+    GoodFit,
+}
 
-static mut ROOT: Block = Block(0 as *mut Header);
-static mut CURRENT_BRK: *mut usize = 0 as *mut usize;
-lazy_static! {
-    static ref MUTEX: Mutex<i32> = Mutex::new(0);
+impl FromStr for SearchStrategy {
+    type Err = ();
+
+    /// Parses the values of `MALLOC_RS_STRATEGY`: `FIRST_FIT`, `BEST_FIT`, `NEXT_FIT`,
+    /// `WORST_FIT` or `GOOD_FIT`.
+    fn from_str(s: &str) -> Result<SearchStrategy, ()> {
+        match s {
+            "FIRST_FIT" => Ok(SearchStrategy::FirstFit),
+            "BEST_FIT" => Ok(SearchStrategy::BestFit),
+            "NEXT_FIT" => Ok(SearchStrategy::NextFit),
+            "WORST_FIT" => Ok(SearchStrategy::WorstFit),
+            "GOOD_FIT" => Ok(SearchStrategy::GoodFit),
+            _ => Err(()),
+        }
+    }
 }
 
-#[allow(dead_code)]
-enum SearchStrategy {
-    FirstFit,
-    BestFit,
+impl SearchStrategy {
+    fn state(self) -> usize {
+        match self {
+            SearchStrategy::FirstFit => STRATEGY_FIRST_FIT,
+            SearchStrategy::BestFit => STRATEGY_BEST_FIT,
+            SearchStrategy::NextFit => STRATEGY_NEXT_FIT,
+            SearchStrategy::WorstFit => STRATEGY_WORST_FIT,
+            SearchStrategy::GoodFit => STRATEGY_GOOD_FIT,
+        }
+    }
+
+    fn from_state(state: usize) -> SearchStrategy {
+        match state {
+            STRATEGY_BEST_FIT => SearchStrategy::BestFit,
+            STRATEGY_NEXT_FIT => SearchStrategy::NextFit,
+            STRATEGY_WORST_FIT => SearchStrategy::WorstFit,
+            STRATEGY_GOOD_FIT => SearchStrategy::GoodFit,
+            _ => SearchStrategy::FirstFit,
+        }
+    }
 }
 
 /// Align size to a multiple of machine word.
@@ -34,162 +147,476 @@ fn align(size: usize) -> usize {
     (size + (size_of::<usize>() - 1)) & !(size_of::<usize>() - 1)
 }
 
-/// Search blocks for free spot or return the last block.
-/// Caller must check the `bool == true` flag if it found spot, otherwise `Block` is the last block.
-fn search_free_spot_or_last(size: usize) -> (Block, bool) {
-    let args: Vec<String> = env::args().collect();
+/// Data size of the block serving a request of `size` bytes: aligned, and large enough to
+/// hold the free list links once the block is freed.
+fn data_size(size: usize) -> usize {
+    align(size).max(MIN_DATA_SIZE)
+}
 
-    let search_strategy = match args.len() {
-        0..=3 => SearchStrategy::FirstFit,
-        _ => match args[3].as_str() {
-            "BEST_FIT" => SearchStrategy::BestFit,
-            "FIRST_FIT" => SearchStrategy::FirstFit,
-            _ => SearchStrategy::FirstFit,
-        },
-    };
+/// Search free blocks with `strategy` from now on, regardless of `MALLOC_RS_STRATEGY`.
+pub fn set_strategy(strategy: SearchStrategy) {
+    STRATEGY.store(strategy.state(), Ordering::Release);
+}
 
-    match search_strategy {
-        SearchStrategy::FirstFit => search_first_fit(size),
-        SearchStrategy::BestFit => search_best_fit(size),
+/// The strategy set with `set_strategy`, or else read from the `MALLOC_RS_STRATEGY`
+/// environment variable at the first allocation.
+///
+/// Reading the environment allocates, so this must run before locking an arena. An allocation
+/// made while the variable is being read (e.g. through `GlobalAlloc`) falls back to `FirstFit`
+/// instead of recursing.
+fn search_strategy() -> SearchStrategy {
+    if STRATEGY.load(Ordering::Acquire) == STRATEGY_UNRESOLVED
+        && STRATEGY
+            .compare_exchange(
+                STRATEGY_UNRESOLVED,
+                STRATEGY_RESOLVING,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            )
+            .is_ok()
+    {
+        let state = read_search_strategy().state();
+        // a strategy set in the meantime wins
+        let _ = STRATEGY.compare_exchange(
+            STRATEGY_RESOLVING,
+            state,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        );
     }
+
+    SearchStrategy::from_state(STRATEGY.load(Ordering::Acquire))
 }
 
-/// Search blocks consecutively and returns the first one fits.
-/// In case no block fits, `bool` is false, and `Block` is the last block.
-fn search_first_fit(size: usize) -> (Block, bool) {
-    let mut current = unsafe { &ROOT };
-    let mut found = false;
+#[cfg(feature = "std")]
+fn read_search_strategy() -> SearchStrategy {
+    env::var("MALLOC_RS_STRATEGY")
+        .ok()
+        .and_then(|strategy| strategy.parse().ok())
+        .unwrap_or(SearchStrategy::FirstFit)
+}
 
-    while current.has_next() {
-        current = current.next();
-        if current.is_free() && current.get_total_size() >= size {
-            found = true;
-            break;
-        }
+/// Without std there is no environment to read.
+#[cfg(not(feature = "std"))]
+fn read_search_strategy() -> SearchStrategy {
+    SearchStrategy::FirstFit
+}
+
+/// The allocator behind `malloc` and friends. Either way, requests above the mmap threshold
+/// get their own mapping.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backend {
+    /// Arenas of `Heap`s with thread caches, searched with the `SearchStrategy`. The default.
+    BlockList,
+    /// A single `Buddy` allocator on the program break, without arenas or thread caches.
+    Buddy,
+    /// A single `TagHeap` on the program break, with one word of overhead per allocation,
+    /// without arenas or thread caches.
+    BoundaryTag,
+}
+
+/// Serve `malloc` and friends from `backend`.
+///
+/// Must be called before the first allocation, returns false afterwards.
+/// `set_heap_buffer` only applies to `Backend::BlockList`.
+pub fn set_backend(backend: Backend) -> bool {
+    let main = arena::main().lock();
+    let buddy = buddy::global().lock();
+    let tag_heap = tag_heap::global().lock();
+    if main.is_initialized() || buddy.is_initialized() || tag_heap.is_initialized() {
+        return false;
     }
 
-    (Block::from_usize(current.0 as usize), found)
+    let state = match backend {
+        Backend::BlockList => BACKEND_BLOCK_LIST,
+        Backend::Buddy => BACKEND_BUDDY,
+        Backend::BoundaryTag => BACKEND_BOUNDARY_TAG,
+    };
+    BACKEND.store(state, Ordering::Release);
+    true
 }
 
-/// Search blocks consecutively and returns the minimum-sized block that still fits `size`.
-fn search_best_fit(size: usize) -> (Block, bool) {
-    let mut current = unsafe { &ROOT };
-    let mut found = false;
-    let mut min = usize::MAX;
-    let mut min_block = current;
+fn use_buddy() -> bool {
+    BACKEND.load(Ordering::Acquire) == BACKEND_BUDDY
+}
 
-    while current.has_next() {
-        current = current.next();
-        let current_total_size = current.get_total_size();
-        if current.is_free() && current_total_size >= size && current_total_size < min {
-            found = true;
-            min = current_total_size;
-            min_block = current;
-        }
-    }
+fn use_boundary_tags() -> bool {
+    BACKEND.load(Ordering::Acquire) == BACKEND_BOUNDARY_TAG
+}
 
-    if found {
-        (Block::from_usize(min_block.0 as usize), found)
-    } else {
-        (Block::from_usize(current.0 as usize), found)
+/// With `Backend::BoundaryTag`, the locked heap holding `ptr`. Its blocks have no `Header`,
+/// so this must be checked before reading one.
+fn tag_heap_of(ptr: *mut usize) -> Option<SpinLockGuard<'static, TagHeap<Brk>>> {
+    if !use_boundary_tags() {
+        return None;
     }
+    let heap = tag_heap::global().lock();
+    heap.contains(ptr).then_some(heap)
 }
 
-unsafe fn brk(end_data_segment: *mut usize) -> *mut isize {
-    let new = syscall1(BRK, end_data_segment as usize);
-    CURRENT_BRK = new as *mut usize;
-    // allow brk to set over end, also handles 0
-    (if new < (end_data_segment as usize) {
-        -1
-    } else {
-        new as isize
-    }) as *mut isize
+/// Let `GoodFit` settle for a free block at most `slack` bytes larger than the request. Zero
+/// makes it behave like `BestFit`.
+pub fn set_good_fit_slack(slack: usize) {
+    GOOD_FIT_SLACK.store(slack, Ordering::Relaxed);
+}
+
+fn good_fit_slack() -> usize {
+    GOOD_FIT_SLACK.load(Ordering::Relaxed)
+}
+
+/// Serve requests of at least `threshold` bytes with their own anonymous mapping instead of
+/// growing the program break. `usize::MAX` disables the mmap path.
+pub fn set_mmap_threshold(threshold: usize) {
+    MMAP_THRESHOLD.store(threshold, Ordering::Relaxed);
+}
+
+fn use_mmap(size: usize) -> bool {
+    size >= MMAP_THRESHOLD.load(Ordering::Relaxed)
 }
 
-unsafe fn sbrk(increment: usize) -> *mut isize {
-    if CURRENT_BRK as usize == 0 {
-        #[allow(clippy::zero_ptr)]
-        brk(0 as *mut usize);
+/// Shrink the program break in `free` once the free last block spans at least `threshold`
+/// bytes. `usize::MAX` disables automatic trimming, `malloc_trim` still works.
+pub fn set_trim_threshold(threshold: usize) {
+    TRIM_THRESHOLD.store(threshold, Ordering::Relaxed);
+}
+
+/// Serve every allocation from `buffer`, e.g. a static array where there is no OS, instead of
+/// the program break. This also turns off the mmap path, the slabs and the non-main arenas,
+/// which would map memory outside of `buffer`.
+///
+/// Must be called before the first allocation, returns false afterwards.
+pub fn set_heap_buffer(buffer: &'static mut [u8]) -> bool {
+    if !arena::set_main_buffer(buffer) {
+        return false;
     }
-    let new = CURRENT_BRK as usize + increment;
-    brk(new as *mut usize)
+    set_mmap_threshold(usize::MAX);
+    set_slab_max(0);
+    set_arena_max(1);
+    true
 }
 
-fn init_malloc() {
-    unsafe {
-        #[allow(clippy::zero_ptr)]
-        let current = brk(0 as *mut usize);
-        ROOT = Block::from_usize(current as usize);
-        sbrk(size_of::<Header>());
-        *ROOT.header() = Header::from_usize(0);
+/// Allocate in the arena of the current thread. Once the heap of a non-main arena is exhausted,
+/// the main arena serves the request instead.
+fn allocate(allocate: impl Fn(&mut Arena) -> (*mut usize, bool)) -> (*mut usize, bool) {
+    {
+        let mut arena = arena::lock();
+        let res = allocate(&mut arena);
+        if !res.0.is_null() || arena.source().is_main() {
+            return res;
+        }
     }
+
+    allocate(&mut arena::main().lock())
 }
 
+/// Returns null if the program break cannot be extended.
 pub fn malloc(size: usize) -> *mut usize {
-    assert!(size > 0);
-    let lock = MUTEX.lock().unwrap();
+    let res = malloc_untraced(size);
+    trace::record(TraceOp::Malloc, size, 0, res);
+    res
+}
 
-    let current_root = unsafe { &ROOT };
-    if current_root.is_null() {
-        init_malloc();
+fn malloc_untraced(size: usize) -> *mut usize {
+    assert!(size > 0);
+    stats::count_malloc();
+    if use_mmap(size) {
+        return mmap::map(data_size(size), size_of::<usize>());
     }
+    let res = slab::alloc(size);
+    if !res.is_null() {
+        return res;
+    }
+    if use_buddy() {
+        return buddy::global().lock().malloc(size);
+    }
+    if use_boundary_tags() {
+        return tag_heap::global().lock().malloc(size);
+    }
+    if let Some(res) = tcache::take(data_size(size)) {
+        return res;
+    }
+    let search_strategy = search_strategy();
 
-    let aligned_size = align(size);
-    let total_size = aligned_size + Block::get_total_padding();
-
-    let (mut block, found) = search_free_spot_or_last(total_size);
+    allocate(|arena| trace::observe(arena, |arena| arena.allocate(size, search_strategy))).0
+}
 
-    let res = if found {
-        // `block` can be reuse
-        // println!("split total_size {:?}", total_size);
-        let new = block.split(aligned_size);
+/// Allocate zero-initialized memory for `count` elements of `size` bytes each.
+///
+/// Returns null if `count * size` overflows or is zero, or if the program break cannot be
+/// extended. Memory freshly obtained from the program break or mmap is already zeroed by the
+/// kernel, so only reused blocks are cleared.
+pub fn calloc(count: usize, size: usize) -> *mut usize {
+    let res = calloc_untraced(count, size);
+    trace::record(TraceOp::Calloc, size, count, res);
+    res
+}
 
-        new.data().unwrap().0
-    } else {
-        // `block` is the last Block, allocate new memory
-        // println!("allocate total_size {:?}", total_size);
-        let current = unsafe { sbrk(0) as *mut usize };
-        unsafe { sbrk(total_size) };
+fn calloc_untraced(count: usize, size: usize) -> *mut usize {
+    let total_size = match count.checked_mul(size) {
+        None | Some(0) => return ptr::null_mut(),
+        Some(total_size) => total_size,
+    };
+    stats::count_malloc();
+    if use_mmap(total_size) {
+        return mmap::map(data_size(total_size), size_of::<usize>());
+    }
+    let res = slab::alloc(total_size);
+    if !res.is_null() {
+        unsafe { ptr::write_bytes(res as *mut u8, 0, total_size) };
+        return res;
+    }
+    if use_buddy() {
+        return buddy::global().lock().calloc(count, size);
+    }
+    if use_boundary_tags() {
+        return tag_heap::global().lock().calloc(count, size);
+    }
 
-        let new = Block::from_usize(current as usize);
-        unsafe {
-            *new.0 = Header::from_usize(aligned_size);
-            new.header().set_prev(Block::from_usize(block.0 as usize));
+    let (res, fresh) = match tcache::take(data_size(total_size)) {
+        Some(res) => (res, false),
+        None => {
+            let search_strategy = search_strategy();
+            allocate(|arena| {
+                trace::observe(arena, |arena| arena.allocate(total_size, search_strategy))
+            })
         }
-        block.header().set_next(Block::from_usize(current as usize));
-
-        new.data().unwrap().0
     };
 
-    Mutex::unlock(lock);
+    if !res.is_null() && !fresh {
+        unsafe { ptr::write_bytes(res as *mut u8, 0, total_size) };
+    }
     res
 }
 
+/// Allocate `size` bytes at an address that is a multiple of `alignment`, which must be a power
+/// of two. The pointer is released with `free` like any other.
+/// Returns null if `size` is zero, `alignment` is invalid or the program break cannot be
+/// extended.
+pub fn memalign(alignment: usize, size: usize) -> *mut usize {
+    let res = memalign_untraced(alignment, size);
+    trace::record(TraceOp::Memalign, size, alignment, res);
+    res
+}
+
+fn memalign_untraced(alignment: usize, size: usize) -> *mut usize {
+    if size == 0 || !alignment.is_power_of_two() {
+        return ptr::null_mut();
+    }
+    stats::count_malloc();
+    if use_mmap(size) {
+        return mmap::map(data_size(size), alignment.max(size_of::<usize>()));
+    }
+    if use_buddy() {
+        return buddy::global().lock().memalign(alignment, size);
+    }
+    if use_boundary_tags() {
+        return tag_heap::global().lock().memalign(alignment, size);
+    }
+    let search_strategy = search_strategy();
+
+    allocate(|arena| {
+        let res = trace::observe(arena, |arena| {
+            arena.allocate_aligned(alignment, size, search_strategy)
+        });
+        (res, false)
+    })
+    .0
+}
+
+/// C11 `aligned_alloc`, see `memalign`.
+pub fn aligned_alloc(alignment: usize, size: usize) -> *mut usize {
+    memalign(alignment, size)
+}
+
+/// POSIX `posix_memalign`: stores the allocation in `memptr` and returns 0, `EINVAL` if
+/// `alignment` is not a power of two multiple of the pointer size, or `ENOMEM`. A zero `size`
+/// stores null.
+pub fn posix_memalign(memptr: &mut *mut usize, alignment: usize, size: usize) -> i32 {
+    if !alignment.is_multiple_of(size_of::<usize>()) || !alignment.is_power_of_two() {
+        return Errno::EINVAL.0;
+    }
+    if size == 0 {
+        *memptr = ptr::null_mut();
+        return 0;
+    }
+
+    let res = memalign(alignment, size);
+    if res.is_null() {
+        return Errno::ENOMEM.0;
+    }
+    *memptr = res;
+    0
+}
+
+/// An invalid pointer or a double free is reported as set by `set_free_check`, see
+/// `check::check_free`, and otherwise left alone.
 pub fn free(ptr: *mut usize) {
+    // before the block can be handed out again
+    trace::record(TraceOp::Free, 0, 0, ptr);
+    free_untraced(ptr);
+}
+
+fn free_untraced(ptr: *mut usize) {
     assert!(ptr as usize != 0);
-    let lock = MUTEX.lock().unwrap();
+    // a slot has no header, check before reading one
+    if slab::contains(ptr) {
+        match slab::check_free(ptr) {
+            Ok(()) => {
+                stats::count_free();
+                slab::free(ptr);
+            }
+            Err(error) => check::report(ptr, error),
+        }
+        return;
+    }
+    if let Some(mut heap) = tag_heap_of(ptr) {
+        if let Err(error) = heap.check_free(ptr) {
+            // reporting may allocate
+            drop(heap);
+            return check::report(ptr, error);
+        }
+        stats::count_free();
+        heap.free(ptr);
+        return;
+    }
+    let block = match check::check_free(ptr) {
+        Ok(block) => block,
+        Err(error) => return check::report(ptr, error),
+    };
+    stats::count_free();
+    if block.header().is_mmapped() {
+        mmap::unmap(&block);
+        return;
+    }
+    if use_buddy() {
+        buddy::global().lock().free(ptr);
+        return;
+    }
+    if tcache::put(&block) {
+        return;
+    }
+
+    release(&block);
+}
+
+/// Put a heap block back into its arena.
+fn release(block: &Block) {
+    let trim_threshold = TRIM_THRESHOLD.load(Ordering::Relaxed);
+    arena::of(block).lock().release(block, trim_threshold);
+}
 
-    let block = Data(ptr).get_block();
-    block.set_free(true);
-    block.coalesce();
-    Mutex::unlock(lock);
+/// Give the memory of the free last block back to the OS, keeping `pad` bytes of it.
+/// Returns true if the program break was lowered.
+/// Only the main arena is trimmed, `Backend::Buddy` and `Backend::BoundaryTag` never shrink.
+pub fn malloc_trim(pad: usize) -> bool {
+    arena::main().lock().trim(pad)
+}
+
+/// Resize the allocation at `ptr` to `size` bytes, keeping its contents.
+///
+/// The block is resized in place whenever possible, mmapped blocks are remapped, otherwise the
+/// data is moved to a new allocation. A null `ptr` behaves like `malloc`, a zero `size` frees
+/// `ptr`, if any, and returns null.
+/// Returns null, leaving `ptr` untouched, if the program break cannot be extended.
+pub fn realloc(ptr: *mut usize, size: usize) -> *mut usize {
+    let res = realloc_untraced(ptr, size);
+    trace::record(TraceOp::Realloc, size, ptr as usize, res);
+    res
+}
+
+/// Calls the untraced functions, a trace shows one record per `realloc`.
+fn realloc_untraced(ptr: *mut usize, size: usize) -> *mut usize {
+    stats::count_realloc();
+    if size == 0 {
+        if !ptr.is_null() {
+            free_untraced(ptr);
+        }
+        return ptr::null_mut();
+    }
+    if ptr.is_null() {
+        return malloc_untraced(size);
+    }
+
+    let available = if slab::contains(ptr) {
+        if let Err(error) = slab::check_free(ptr) {
+            check::report(ptr, error);
+            return ptr::null_mut();
+        }
+        let slot_size = slab::slot_size(ptr);
+        if size <= slot_size {
+            return ptr;
+        }
+        slot_size
+    } else if let Some(mut heap) = tag_heap_of(ptr) {
+        if let Err(error) = heap.check_free(ptr) {
+            drop(heap);
+            check::report(ptr, error);
+            return ptr::null_mut();
+        }
+        if heap.resize_in_place(ptr, size) {
+            return ptr;
+        }
+        heap.usable_size(ptr)
+    } else {
+        let block = match check::check_free(ptr) {
+            Ok(block) => block,
+            Err(error) => {
+                check::report(ptr, error);
+                return ptr::null_mut();
+            }
+        };
+        if block.header().is_mmapped() {
+            if data_size(size) <= block.get_data_size() {
+                return ptr;
+            }
+            let new = mmap::remap(&block, data_size(size));
+            if !new.is_null() {
+                return new;
+            }
+        } else if use_buddy() {
+            if align(size) <= block.get_data_size() {
+                return ptr;
+            }
+        } else {
+            let mut arena = arena::of(&block).lock();
+            if trace::observe(&mut arena, |arena| {
+                arena.resize_in_place(&block, data_size(size))
+            }) {
+                return ptr;
+            }
+        }
+        block.get_data_size()
+    };
+
+    let new = malloc_untraced(size);
+    if !new.is_null() {
+        unsafe { ptr::copy_nonoverlapping(ptr as *const u8, new as *mut u8, available.min(size)) };
+        free_untraced(ptr);
+    }
+    new
 }
 
 #[cfg(test)]
 mod tests {
     use crate::malloc::align;
-    use crate::malloc::init_malloc;
+    use crate::malloc::data_size;
     use lazy_static::lazy_static;
+    use std::ptr;
     use std::sync::Mutex;
 
-    use super::brk;
+    use super::calloc;
     use super::free;
     use super::malloc;
-
-    // TODO find a better way.
-    // Currently malloc tests requires to run in sequence (w.r.t ALL test) to keep track of memory using brk
+    use super::memalign;
+    use super::posix_memalign;
+    use super::realloc;
+    use super::SearchStrategy;
+    use super::DEFAULT_MMAP_THRESHOLD;
+
+    // Tests of the process-wide heap look at blocks other tests could reuse, or change its
+    // settings, so they run one at a time. Tests of a `Heap` of their own run in parallel.
     lazy_static! {
-        static ref MUTEX: Mutex<i32> = Mutex::new(0);
+        pub(crate) static ref MUTEX: Mutex<i32> = Mutex::new(0);
     }
 
     #[test]
@@ -206,63 +633,108 @@ mod tests {
     }
 
     #[test]
-    fn test_malloc() {
-        let lock = MUTEX.lock().unwrap();
-        init_malloc();
-        let total_size =
-            |data| -> usize { crate::malloc::Block::get_total_padding() + align(data) };
-
-        let initial_brk = unsafe { brk(0 as *mut usize) as usize };
-        println!("initial_brk {:?}", initial_brk);
-
-        let requests = [3, 4, 8, 13, 28, 321];
-        let mut counter_size = 0;
-        for request in requests {
-            malloc(request);
-            counter_size += total_size(request);
-        }
+    fn test_calloc_overflow() {
+        let _lock = MUTEX.lock().unwrap();
+        let before = super::malloc_stats().mallocs;
+        assert!(calloc(usize::MAX, 2).is_null());
+        assert!(calloc(0, 8).is_null());
+        assert!(memalign(24, 8).is_null());
+        // rejected calls are not counted
+        assert_eq!(before, super::malloc_stats().mallocs);
+    }
 
-        let final_brk = unsafe { brk(0 as *mut usize) as usize };
-        println!("final_brk {:?}", final_brk);
+    #[test]
+    fn test_realloc_zero_size() {
+        let _lock = MUTEX.lock().unwrap();
+        assert!(realloc(ptr::null_mut(), 0).is_null());
+        let tmp = malloc(64);
+        assert!(realloc(tmp, 0).is_null());
+    }
 
-        assert_eq!(initial_brk + counter_size, final_brk);
-        Mutex::unlock(lock);
+    #[test]
+    fn test_data_size() {
+        assert_eq!(16, data_size(1));
+        assert_eq!(16, data_size(16));
+        assert_eq!(24, data_size(17));
     }
 
     #[test]
-    fn test_free() {
-        let lock = MUTEX.lock().unwrap();
-        init_malloc();
-        let total_size =
-            |data| -> usize { crate::malloc::Block::get_total_padding() + align(data) };
+    fn test_search_strategy_from_str() {
+        assert_eq!(Ok(SearchStrategy::BestFit), "BEST_FIT".parse());
+        assert_eq!(Ok(SearchStrategy::FirstFit), "FIRST_FIT".parse());
+        assert_eq!(Err(()), "best_fit".parse::<SearchStrategy>());
+        assert_eq!(Ok(SearchStrategy::NextFit), "NEXT_FIT".parse());
+        assert_eq!(Ok(SearchStrategy::WorstFit), "WORST_FIT".parse());
+        assert_eq!(Ok(SearchStrategy::GoodFit), "GOOD_FIT".parse());
+        for strategy in [
+            SearchStrategy::FirstFit,
+            SearchStrategy::BestFit,
+            SearchStrategy::NextFit,
+            SearchStrategy::WorstFit,
+            SearchStrategy::GoodFit,
+        ] {
+            assert_eq!(strategy, SearchStrategy::from_state(strategy.state()));
+        }
+    }
 
-        init_malloc();
+    #[test]
+    fn test_posix_memalign() {
+        let _lock = MUTEX.lock().unwrap();
+        let mut tmp = ptr::null_mut();
+        assert_eq!(super::Errno::EINVAL.0, posix_memalign(&mut tmp, 4, 8));
+        assert_eq!(super::Errno::EINVAL.0, posix_memalign(&mut tmp, 24, 8));
+        assert!(tmp.is_null());
+
+        assert_eq!(0, posix_memalign(&mut tmp, 64, 8));
+        assert_eq!(0, tmp as usize % 64);
+        free(tmp);
 
-        let initial_brk = unsafe { brk(0 as *mut usize) as usize };
-        println!("initial_brk {:?}", initial_brk);
+        assert_eq!(0, posix_memalign(&mut tmp, 64, 0));
+        assert!(tmp.is_null());
+        assert!(super::aligned_alloc(64, 0).is_null());
+    }
 
-        let mut tmp = malloc(18);
-        free(tmp);
-        tmp = malloc(17);
-        free(tmp);
-        tmp = malloc(18);
-        free(tmp);
-        tmp = malloc(24);
-        free(tmp);
-        let tmp1 = malloc(1048576);
-        let tmp2 = malloc(1048576);
-        let tmp3 = malloc(1048576);
-        free(tmp1);
-        free(tmp2);
-        free(tmp3);
-        tmp = malloc(3145728);
-        free(tmp);
+    #[test]
+    fn test_mmap_large_allocation() {
+        let _lock = MUTEX.lock().unwrap();
+        let large = malloc(DEFAULT_MMAP_THRESHOLD);
+        unsafe { (large as *mut u8).write_bytes(0xab, DEFAULT_MMAP_THRESHOLD) };
+
+        let block = super::types::Data(large).get_block();
+        assert!(block.header().is_mmapped());
+        assert!(!block.has_next());
+        free(large);
+
+        let zeroed = calloc(DEFAULT_MMAP_THRESHOLD, 2);
+        assert!((0..DEFAULT_MMAP_THRESHOLD / 4).all(|i| unsafe { *zeroed.add(i) } == 0));
+        free(zeroed);
+    }
+
+    #[test]
+    fn test_mmap_realloc() {
+        let _lock = MUTEX.lock().unwrap();
+        let large = malloc(DEFAULT_MMAP_THRESHOLD);
+        unsafe {
+            *large = 42;
+            *large.add(DEFAULT_MMAP_THRESHOLD / 8 - 1) = 43;
+        }
 
-        let final_brk = unsafe { brk(0 as *mut usize) as usize };
-        println!("final_brk {:?}", final_brk);
+        let grown = realloc(large, 4 * DEFAULT_MMAP_THRESHOLD);
+        assert_eq!(unsafe { *grown }, 42);
+        assert_eq!(unsafe { *grown.add(DEFAULT_MMAP_THRESHOLD / 8 - 1) }, 43);
+        unsafe { (grown as *mut u8).write_bytes(0xab, 4 * DEFAULT_MMAP_THRESHOLD) };
+        free(grown);
+    }
 
-        let max = 3 * total_size(1048576) + total_size(24);
-        assert_eq!(initial_brk + max, final_brk);
-        Mutex::unlock(lock);
+    #[test]
+    fn test_mmap_memalign() {
+        let _lock = MUTEX.lock().unwrap();
+        for alignment in [8, 64, 4096, 65536] {
+            let large = memalign(alignment, DEFAULT_MMAP_THRESHOLD);
+            assert_eq!(0, large as usize % alignment);
+            assert!(super::types::Data(large).get_block().header().is_mmapped());
+            unsafe { (large as *mut u8).write_bytes(0xab, DEFAULT_MMAP_THRESHOLD) };
+            free(large);
+        }
     }
 }
@@ -0,0 +1,465 @@
+use core::marker::PhantomData;
+use core::mem::{align_of, size_of};
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::align;
+use super::check::FreeError;
+use super::lock::SpinLock;
+use super::mmap::{self, PAGE_SIZE};
+use super::source::{MemorySource, MmapRegion};
+
+const BITMAP_WORDS: usize = 8;
+/// Slots per slab, one bit each in the bitmap.
+const MAX_SLOTS: usize = BITMAP_WORDS * u64::BITS as usize;
+/// A `SlabCache` slab spans at least this many slots, so large objects don't get a slab each.
+const MIN_SLOTS: usize = 8;
+
+/// Largest request `set_slab_max` lets `malloc` serve from slabs.
+pub const MAX_SLAB_SIZE: usize = 256;
+static SLAB_MAX: AtomicUsize = AtomicUsize::new(0);
+
+/// One slab cache per word-sized class up to `MAX_SLAB_SIZE`, each slab a page.
+const NUM_CLASSES: usize = MAX_SLAB_SIZE / size_of::<usize>();
+static CLASSES: [SpinLock<Slabs>; NUM_CLASSES] = classes();
+
+/// Reserved on first use for the slabs of `malloc`, so `free` recognizes a slot by its address.
+/// Only the pages that are touched take up memory.
+const REGION_SIZE: usize = 64 * 1024 * 1024;
+static REGION: SpinLock<Pages> = SpinLock::new(Pages {
+    region: None,
+    free: ptr::null_mut(),
+});
+/// Start of the region once it is mapped, readable without the lock.
+static REGION_BASE: AtomicUsize = AtomicUsize::new(0);
+
+/// Header at the start of every slab, followed by its slots.
+#[repr(C)]
+struct Slab {
+    prev: *mut Slab,
+    next: *mut Slab,
+    slot_size: usize,
+    /// Slots in use.
+    used: usize,
+    /// A set bit marks a slot in use. Bits past the last slot are always set.
+    bitmap: [u64; BITMAP_WORDS],
+}
+
+/// Slabs of equally sized slots, each slab aligned to its size so a slot finds its slab by
+/// masking its address.
+///
+/// Slabs with a free slot are kept apart from full ones, so allocating only looks at the first
+/// partial slab.
+pub struct Slabs {
+    slot_size: usize,
+    slab_size: usize,
+    /// Offset of the first slot, past the header and aligned for the slots.
+    offset: usize,
+    slots: usize,
+    partial: *mut Slab,
+    full: *mut Slab,
+}
+
+// slabs are only reachable through their list
+unsafe impl Send for Slabs {}
+
+/// Pages of the region, handed out bottom up and reused once their slab is empty.
+struct Pages {
+    region: Option<MmapRegion>,
+    /// Singly linked through the first word of every free page.
+    free: *mut usize,
+}
+
+unsafe impl Send for Pages {}
+
+/// A cache of slots for `T`, carved out of mapped slabs. A slot takes `size_of::<T>()` bytes
+/// without a `Header` and is found without a search.
+///
+/// Dropping the cache unmaps every slab, including slots that were not freed.
+///
+/// ```
+/// use malloc_rs::malloc::SlabCache;
+///
+/// let mut cache = SlabCache::<[u64; 3]>::new();
+/// let slot = cache.alloc();
+/// unsafe { slot.write([1, 2, 3]) };
+/// cache.free(slot);
+/// ```
+pub struct SlabCache<T> {
+    slabs: Slabs,
+    _marker: PhantomData<T>,
+}
+
+const fn classes() -> [SpinLock<Slabs>; NUM_CLASSES] {
+    let mut classes = [const { SpinLock::new(Slabs::new(0, 1, PAGE_SIZE)) }; NUM_CLASSES];
+    let mut index = 0;
+    while index < NUM_CLASSES {
+        let slot_size = (index + 1) * size_of::<usize>();
+        classes[index] = SpinLock::new(Slabs::new(slot_size, size_of::<usize>(), PAGE_SIZE));
+        index += 1;
+    }
+    classes
+}
+
+/// Serve `malloc` requests of at most `max` bytes from slabs, clamped to `MAX_SLAB_SIZE`.
+/// Zero, the default, turns the slabs off for allocations from now on.
+pub fn set_slab_max(max: usize) {
+    SLAB_MAX.store(max.min(MAX_SLAB_SIZE), Ordering::Relaxed);
+}
+
+/// Take a slot for `size` bytes, or null if slabs don't serve `size` or the region is exhausted.
+pub fn alloc(size: usize) -> *mut usize {
+    if size > SLAB_MAX.load(Ordering::Relaxed) {
+        return ptr::null_mut();
+    }
+
+    let mut slabs = CLASSES[align(size) / size_of::<usize>() - 1].lock();
+    let mut slot = slabs.alloc();
+    if slot.is_null() {
+        let page = REGION.lock().take();
+        if page.is_null() {
+            return ptr::null_mut();
+        }
+        slabs.add(page);
+        slot = slabs.alloc();
+    }
+    slot as *mut usize
+}
+
+/// Whether `ptr` is a slot of `malloc`'s slabs.
+pub fn contains(ptr: *mut usize) -> bool {
+    let base = REGION_BASE.load(Ordering::Acquire);
+    base != 0 && (ptr as usize).wrapping_sub(base) < REGION_SIZE
+}
+
+/// Size of the slot at `ptr`.
+pub fn slot_size(ptr: *mut usize) -> usize {
+    unsafe { (*Slabs::slab_of(ptr as *mut u8, PAGE_SIZE)).slot_size }
+}
+
+/// Check that `ptr` is the start of a slot in use before it is freed.
+pub fn check_free(ptr: *mut usize) -> Result<(), FreeError> {
+    let slot_size = slot_size(ptr);
+    if slot_size == 0 || slot_size > MAX_SLAB_SIZE {
+        return Err(FreeError::OutOfBounds);
+    }
+    let class = slot_size / size_of::<usize>() - 1;
+    CLASSES[class].lock().check_free(ptr as *mut u8)
+}
+
+pub fn free(ptr: *mut usize) {
+    let class = slot_size(ptr) / size_of::<usize>() - 1;
+    let empty = CLASSES[class].lock().free(ptr as *mut u8);
+    if !empty.is_null() {
+        REGION.lock().give(empty);
+    }
+}
+
+impl Slabs {
+    /// Slabs of `slab_size` bytes, a power of two, with slots of `slot_size` bytes at multiples
+    /// of `align`.
+    pub const fn new(slot_size: usize, align: usize, slab_size: usize) -> Slabs {
+        let offset = (size_of::<Slab>() + align - 1) & !(align - 1);
+        // the placeholders of `classes` have no slots
+        let mut slots = match (slab_size - offset).checked_div(slot_size) {
+            Some(slots) => slots,
+            None => 0,
+        };
+        if slots > MAX_SLOTS {
+            slots = MAX_SLOTS;
+        }
+        Slabs {
+            slot_size,
+            slab_size,
+            offset,
+            slots,
+            partial: ptr::null_mut(),
+            full: ptr::null_mut(),
+        }
+    }
+
+    fn slab_of(slot: *mut u8, slab_size: usize) -> *mut Slab {
+        (slot as usize & !(slab_size - 1)) as *mut Slab
+    }
+
+    /// Take a free slot, or null if every slab is full.
+    pub fn alloc(&mut self) -> *mut u8 {
+        let slab = self.partial;
+        if slab.is_null() {
+            return ptr::null_mut();
+        }
+
+        let slab_ref = unsafe { &mut *slab };
+        let (word, bits) = slab_ref
+            .bitmap
+            .iter_mut()
+            .enumerate()
+            .find(|(_, bits)| **bits != u64::MAX)
+            .unwrap();
+        let bit = bits.trailing_ones() as usize;
+        *bits |= 1 << bit;
+        slab_ref.used += 1;
+        if slab_ref.used == self.slots {
+            unlink(&mut self.partial, slab);
+            link(&mut self.full, slab);
+        }
+
+        let index = word * u64::BITS as usize + bit;
+        (slab as usize + self.offset + index * self.slot_size) as *mut u8
+    }
+
+    /// Turn `memory`, `slab_size` bytes aligned to their size, into an empty slab.
+    pub fn add(&mut self, memory: *mut u8) {
+        let slab = memory as *mut Slab;
+        let mut bitmap = [0; BITMAP_WORDS];
+        for (word, bits) in bitmap.iter_mut().enumerate() {
+            let first = word * u64::BITS as usize;
+            if self.slots <= first {
+                *bits = u64::MAX;
+            } else if self.slots - first < u64::BITS as usize {
+                *bits = u64::MAX << (self.slots - first);
+            }
+        }
+        unsafe {
+            *slab = Slab {
+                prev: ptr::null_mut(),
+                next: ptr::null_mut(),
+                slot_size: self.slot_size,
+                used: 0,
+                bitmap,
+            }
+        };
+        link(&mut self.partial, slab);
+    }
+
+    /// Whether `ptr` starts a slot of this class that is in use.
+    fn check_free(&self, ptr: *mut u8) -> Result<(), FreeError> {
+        let slab = Slabs::slab_of(ptr, self.slab_size);
+        let offset = (ptr as usize - slab as usize).wrapping_sub(self.offset);
+        if !offset.is_multiple_of(self.slot_size) {
+            return Err(FreeError::Misaligned);
+        }
+        let index = offset / self.slot_size;
+        if index >= self.slots {
+            return Err(FreeError::OutOfBounds);
+        }
+        let bits = unsafe { (*slab).bitmap[index / u64::BITS as usize] };
+        if bits & 1 << (index % u64::BITS as usize) == 0 {
+            return Err(FreeError::DoubleFree);
+        }
+        Ok(())
+    }
+
+    /// Give back the slot at `ptr`. Returns its slab once it is empty and another slab has free
+    /// slots, for the caller to release, or null.
+    pub fn free(&mut self, ptr: *mut u8) -> *mut u8 {
+        let slab = Slabs::slab_of(ptr, self.slab_size);
+        let slab_ref = unsafe { &mut *slab };
+        let index = (ptr as usize - slab as usize - self.offset) / self.slot_size;
+        let bit = 1 << (index % u64::BITS as usize);
+        let bits = &mut slab_ref.bitmap[index / u64::BITS as usize];
+        debug_assert!(*bits & bit != 0, "slot is not in use");
+        *bits &= !bit;
+
+        if slab_ref.used == self.slots {
+            unlink(&mut self.full, slab);
+            link(&mut self.partial, slab);
+        }
+        slab_ref.used -= 1;
+
+        // keep the last partial slab around, so alternating alloc and free doesn't map pages
+        if slab_ref.used == 0 && !(slab_ref.next.is_null() && slab_ref.prev.is_null()) {
+            unlink(&mut self.partial, slab);
+            return slab as *mut u8;
+        }
+        ptr::null_mut()
+    }
+
+    /// Unlink every slab, calling `release` with each.
+    fn drain(&mut self, mut release: impl FnMut(*mut u8)) {
+        for list in [&mut self.partial, &mut self.full] {
+            while !list.is_null() {
+                let slab = *list;
+                unlink(list, slab);
+                release(slab as *mut u8);
+            }
+        }
+    }
+}
+
+fn link(list: &mut *mut Slab, slab: *mut Slab) {
+    unsafe {
+        (*slab).prev = ptr::null_mut();
+        (*slab).next = *list;
+        if !list.is_null() {
+            (**list).prev = slab;
+        }
+    }
+    *list = slab;
+}
+
+fn unlink(list: &mut *mut Slab, slab: *mut Slab) {
+    unsafe {
+        let Slab { prev, next, .. } = *slab;
+        if prev.is_null() {
+            *list = next;
+        } else {
+            (*prev).next = next;
+        }
+        if !next.is_null() {
+            (*next).prev = prev;
+        }
+        (*slab).prev = ptr::null_mut();
+        (*slab).next = ptr::null_mut();
+    }
+}
+
+impl Pages {
+    /// Returns a page, or null if the region is exhausted or cannot be mapped.
+    fn take(&mut self) -> *mut u8 {
+        if !self.free.is_null() {
+            let page = self.free;
+            self.free = unsafe { *page } as *mut usize;
+            return page as *mut u8;
+        }
+
+        if self.region.is_none() {
+            self.region = MmapRegion::new(REGION_SIZE);
+            match &self.region {
+                Some(region) => REGION_BASE.store(region.base(), Ordering::Release),
+                None => return ptr::null_mut(),
+            }
+        }
+        self.region.as_mut().unwrap().extend(PAGE_SIZE)
+    }
+
+    fn give(&mut self, page: *mut u8) {
+        let page = page as *mut usize;
+        unsafe { *page = self.free as usize };
+        self.free = page;
+    }
+}
+
+impl<T> SlabCache<T> {
+    /// Create an empty cache, slabs are mapped as slots are needed.
+    pub const fn new() -> SlabCache<T> {
+        let slot_size = if size_of::<T>() == 0 {
+            1
+        } else {
+            size_of::<T>()
+        };
+        let align = align_of::<T>();
+        let slot_size = (slot_size + align - 1) & !(align - 1);
+
+        let offset = (size_of::<Slab>() + align - 1) & !(align - 1);
+        let mut slab_size = (offset + slot_size * MIN_SLOTS).next_power_of_two();
+        if slab_size < PAGE_SIZE {
+            slab_size = PAGE_SIZE;
+        }
+        SlabCache {
+            slabs: Slabs::new(slot_size, align, slab_size),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Returns an uninitialized slot for a `T`, or null if no slab can be mapped.
+    pub fn alloc(&mut self) -> *mut T {
+        let slot = self.slabs.alloc();
+        if !slot.is_null() {
+            return slot as *mut T;
+        }
+
+        let slab_size = self.slabs.slab_size;
+        let memory = match unsafe { mmap::mmap_aligned(slab_size, slab_size) } {
+            Ok(memory) => memory as *mut u8,
+            Err(_) => return ptr::null_mut(),
+        };
+        self.slabs.add(memory);
+        self.slabs.alloc() as *mut T
+    }
+
+    /// Give back a slot taken from this cache. The `T` in it is not dropped.
+    pub fn free(&mut self, slot: *mut T) {
+        let empty = self.slabs.free(slot as *mut u8);
+        if !empty.is_null() {
+            let _ = unsafe { mmap::munmap(empty as usize, self.slabs.slab_size) };
+        }
+    }
+}
+
+impl<T> Default for SlabCache<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for SlabCache<T> {
+    fn drop(&mut self) {
+        let slab_size = self.slabs.slab_size;
+        self.slabs.drain(|slab| {
+            let _ = unsafe { mmap::munmap(slab as usize, slab_size) };
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::{SlabCache, MAX_SLOTS};
+    use crate::malloc::mmap::PAGE_SIZE;
+
+    #[test]
+    fn test_reuse() {
+        let mut cache = SlabCache::<[usize; 3]>::new();
+        let first = cache.alloc();
+        let second = cache.alloc();
+        assert_eq!(first as usize + 24, second as usize);
+        assert_eq!(
+            first as usize & !(PAGE_SIZE - 1),
+            second as usize & !(PAGE_SIZE - 1)
+        );
+
+        cache.free(first);
+        assert_eq!(first, cache.alloc());
+    }
+
+    #[test]
+    fn test_fill_slabs() {
+        let mut cache = SlabCache::<u64>::new();
+        let slots: Vec<_> = (0..2 * MAX_SLOTS).map(|_| cache.alloc()).collect();
+        let slabs: HashSet<_> = slots
+            .iter()
+            .map(|slot| *slot as usize & !(PAGE_SIZE - 1))
+            .collect();
+        assert!(slabs.len() > 2);
+        assert_eq!(slots.len(), slots.iter().collect::<HashSet<_>>().len());
+        for (i, slot) in slots.iter().enumerate() {
+            unsafe { slot.write(i as u64) };
+        }
+        for (i, slot) in slots.iter().enumerate() {
+            assert_eq!(i as u64, unsafe { slot.read() });
+            cache.free(*slot);
+        }
+
+        // only the last partial slab is kept
+        assert!(cache.slabs.full.is_null());
+        assert!(unsafe { (*cache.slabs.partial).next }.is_null());
+    }
+
+    #[test]
+    fn test_large_and_aligned_objects() {
+        #[repr(align(64))]
+        struct Aligned([u8; 600]);
+
+        let mut cache = SlabCache::<Aligned>::new();
+        let first = cache.alloc();
+        let second = cache.alloc();
+        assert_eq!(0, first as usize % 64);
+        assert_eq!(first as usize + 640, second as usize);
+        unsafe { first.write(Aligned([1; 600])) };
+        assert_eq!(1, unsafe { (*first).0[599] });
+        cache.free(second);
+        cache.free(first);
+    }
+}
@@ -0,0 +1,216 @@
+use core::mem::size_of;
+use core::ptr;
+
+use super::mmap::{self, page_align};
+use super::syscalls::{syscall1, BRK};
+
+static mut CURRENT_BRK: *mut usize = ptr::null_mut();
+
+/// Where a `Heap` gets its memory from.
+///
+/// The memory is one contiguous range that grows and shrinks at its top: every `extend`
+/// continues where the previous one ended.
+pub trait MemorySource {
+    /// Grow by `increment` bytes, returns the start of the new memory or null if the source
+    /// is exhausted.
+    fn extend(&mut self, increment: usize) -> *mut u8;
+
+    /// End of the memory handed out so far.
+    fn top(&self) -> usize;
+
+    /// Give the memory above `top`, a page boundary below the current top, back to the OS.
+    /// Returns false if the source cannot shrink.
+    fn shrink(&mut self, _top: usize) -> bool {
+        false
+    }
+
+    /// Whether memory returned by `extend` is always zeroed, so `calloc` can skip clearing it.
+    fn is_zeroed(&self) -> bool {
+        false
+    }
+}
+
+unsafe fn brk(end_data_segment: *mut usize) -> *mut isize {
+    // brk reports failure by returning the unchanged break rather than an errno
+    let new = syscall1(BRK, end_data_segment as usize).unwrap_or(CURRENT_BRK as usize);
+    CURRENT_BRK = new as *mut usize;
+    // allow brk to set over end, also handles 0
+    (if new < (end_data_segment as usize) {
+        -1
+    } else {
+        new as isize
+    }) as *mut isize
+}
+
+unsafe fn sbrk(increment: usize) -> *mut isize {
+    if CURRENT_BRK as usize == 0 {
+        #[allow(clippy::zero_ptr)]
+        brk(0 as *mut usize);
+    }
+    let new = CURRENT_BRK as usize + increment;
+    brk(new as *mut usize)
+}
+
+/// The program break of the process.
+///
+/// There is only one program break and the main arena behind `malloc` grows it, so only use
+/// this for a `Heap` in a process that doesn't use the global functions.
+pub struct Brk;
+
+impl MemorySource for Brk {
+    fn extend(&mut self, increment: usize) -> *mut u8 {
+        unsafe {
+            let current = sbrk(0);
+            if sbrk(increment) as isize == -1 {
+                return ptr::null_mut();
+            }
+            current as *mut u8
+        }
+    }
+
+    fn top(&self) -> usize {
+        unsafe { sbrk(0) as usize }
+    }
+
+    fn shrink(&mut self, top: usize) -> bool {
+        // a refused shrink leaves the break above `top`, which `brk` doesn't count as failure
+        unsafe { brk(top as *mut usize) as usize == top }
+    }
+
+    /// Memory above the break is zeroed as long as the break is only lowered to page
+    /// boundaries.
+    fn is_zeroed(&self) -> bool {
+        true
+    }
+}
+
+/// A private anonymous mapping reserved up front. Only the pages that are touched take up
+/// memory, and the mapping is released when the region is dropped.
+pub struct MmapRegion {
+    base: usize,
+    top: usize,
+    end: usize,
+}
+
+impl MmapRegion {
+    /// Reserve `len` bytes, returns `None` if the mapping fails.
+    pub fn new(len: usize) -> Option<MmapRegion> {
+        let len = page_align(len);
+        let base = unsafe { mmap::mmap(len) }.ok()?;
+        Some(MmapRegion {
+            base,
+            top: base,
+            end: base + len,
+        })
+    }
+
+    /// Reserve `len` bytes starting at a multiple of `alignment`, a power of two.
+    pub fn aligned(len: usize, alignment: usize) -> Option<MmapRegion> {
+        let len = page_align(len);
+        let start = unsafe { mmap::mmap_aligned(len, alignment) }.ok()?;
+        Some(MmapRegion {
+            base: start,
+            top: start,
+            end: start + len,
+        })
+    }
+
+    pub fn base(&self) -> usize {
+        self.base
+    }
+}
+
+impl MemorySource for MmapRegion {
+    fn extend(&mut self, increment: usize) -> *mut u8 {
+        if self.end - self.top < increment {
+            return ptr::null_mut();
+        }
+        self.top += increment;
+        (self.top - increment) as *mut u8
+    }
+
+    fn top(&self) -> usize {
+        self.top
+    }
+
+    /// Pages above `top` are dropped, the next access finds them zeroed.
+    fn shrink(&mut self, top: usize) -> bool {
+        if unsafe { mmap::discard(top, page_align(self.top) - top) }.is_err() {
+            return false;
+        }
+        self.top = top;
+        true
+    }
+
+    fn is_zeroed(&self) -> bool {
+        true
+    }
+}
+
+impl Drop for MmapRegion {
+    fn drop(&mut self) {
+        let _ = unsafe { mmap::munmap(self.base, self.end - self.base) };
+    }
+}
+
+/// A buffer provided by the caller, e.g. a static array where no OS is around.
+pub struct Slice<'a> {
+    memory: &'a mut [u8],
+    used: usize,
+}
+
+impl<'a> Slice<'a> {
+    pub fn new(memory: &'a mut [u8]) -> Slice<'a> {
+        // blocks must be word aligned, skip the misaligned start
+        let misalignment = memory.as_ptr() as usize % size_of::<usize>();
+        let used = if misalignment == 0 {
+            0
+        } else {
+            (size_of::<usize>() - misalignment).min(memory.len())
+        };
+        Slice { memory, used }
+    }
+}
+
+impl MemorySource for Slice<'_> {
+    fn extend(&mut self, increment: usize) -> *mut u8 {
+        if self.memory.len() - self.used < increment {
+            return ptr::null_mut();
+        }
+        self.used += increment;
+        unsafe { self.memory.as_mut_ptr().add(self.used - increment) }
+    }
+
+    fn top(&self) -> usize {
+        self.memory.as_ptr() as usize + self.used
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MemorySource, MmapRegion, Slice};
+
+    #[test]
+    fn test_mmap_region() {
+        let mut region = MmapRegion::aligned(8192, 1 << 20).unwrap();
+        assert_eq!(0, region.base() % (1 << 20));
+        let first = region.extend(4096);
+        assert_eq!(region.base(), first as usize);
+        unsafe { first.write_bytes(0xab, 4096) };
+        assert!(region.extend(8192).is_null());
+
+        assert!(region.shrink(region.base()));
+        assert_eq!(first, region.extend(4096));
+        assert_eq!(0, unsafe { *first.add(100) });
+    }
+
+    #[test]
+    fn test_slice() {
+        let mut memory = [0u8; 100];
+        let mut slice = Slice::new(&mut memory[1..]);
+        let first = slice.extend(16);
+        assert_eq!(0, first as usize % 8);
+        assert_eq!(first as usize + 16, slice.top());
+        assert!(slice.extend(100).is_null());
+    }
+}
@@ -0,0 +1,309 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::arena;
+use super::heap::Heap;
+use super::source::MemorySource;
+use super::types::Block;
+
+static MALLOCS: AtomicUsize = AtomicUsize::new(0);
+static FREES: AtomicUsize = AtomicUsize::new(0);
+static REALLOCS: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of buckets of a `Histogram`, one per bit of a size.
+pub const HISTOGRAM_BUCKETS: usize = usize::BITS as usize;
+
+/// Cumulative counters of a `Heap`, updated under its lock.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Counters {
+    /// Blocks split to serve a smaller request.
+    pub splits: usize,
+    /// Free neighbours merged into another block.
+    pub coalesces: usize,
+    /// Times the memory source, e.g. the program break, was extended.
+    pub grows: usize,
+    /// Times the memory source was shrunk.
+    pub trims: usize,
+    /// Largest size the heap ever had, from the root block to the top.
+    pub peak_bytes: usize,
+}
+
+/// Count of free blocks per power of two: bucket `i` holds the blocks with a data size in
+/// `[2^i, 2^(i+1))`, empty blocks go to bucket 0.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Histogram(pub [usize; HISTOGRAM_BUCKETS]);
+
+/// A snapshot of the heap, like glibc's `mallinfo`, see `malloc_stats`.
+///
+/// Blocks held by thread caches count as in use. Slabs, other backends and mmapped blocks are
+/// not included, only their calls are counted.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MallocStats {
+    /// Bytes from the root block to the top of the memory, e.g. the program break, summed over
+    /// the arenas.
+    pub arena_bytes: usize,
+    /// The largest `arena_bytes` each arena ever had, summed.
+    pub peak_arena_bytes: usize,
+    /// Data bytes of the blocks in use.
+    pub used_bytes: usize,
+    /// Data bytes of the free blocks.
+    pub free_bytes: usize,
+    pub used_blocks: usize,
+    pub free_blocks: usize,
+    /// Data bytes of the largest free block.
+    pub largest_free: usize,
+    /// Bytes taken by the headers of all blocks, including the root blocks.
+    pub header_bytes: usize,
+    /// Sizes of the free blocks.
+    pub free_histogram: Histogram,
+    /// Calls to `malloc`, `calloc` and the aligned variants, including those made by `realloc`.
+    /// Calls rejected for their arguments are not counted.
+    pub mallocs: usize,
+    /// Calls to `free`, including those made by `realloc`. Pointers refused as invalid are
+    /// counted by `invalid_frees` instead.
+    pub frees: usize,
+    pub reallocs: usize,
+    pub splits: usize,
+    pub coalesces: usize,
+    pub grows: usize,
+    pub trims: usize,
+}
+
+impl Counters {
+    pub const fn new() -> Counters {
+        Counters {
+            splits: 0,
+            coalesces: 0,
+            grows: 0,
+            trims: 0,
+            peak_bytes: 0,
+        }
+    }
+}
+
+pub fn count_malloc() {
+    MALLOCS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn count_free() {
+    FREES.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn count_realloc() {
+    REALLOCS.fetch_add(1, Ordering::Relaxed);
+}
+
+impl Histogram {
+    /// The bucket of a block with `size` data bytes.
+    pub fn bucket(size: usize) -> usize {
+        size.max(1).ilog2() as usize
+    }
+
+    fn add(&mut self, size: usize) {
+        self.0[Histogram::bucket(size)] += 1;
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Histogram {
+        Histogram([0; HISTOGRAM_BUCKETS])
+    }
+}
+
+impl MallocStats {
+    /// External fragmentation, `1 - largest_free / free_bytes`: 0 if all free memory is in one
+    /// block or there is none, close to 1 if it is scattered over many small blocks.
+    pub fn fragmentation(&self) -> f64 {
+        if self.free_bytes == 0 {
+            return 0.0;
+        }
+        1.0 - self.largest_free as f64 / self.free_bytes as f64
+    }
+
+    /// Add the totals and counters of `other`.
+    fn add(&mut self, other: &MallocStats) {
+        self.arena_bytes += other.arena_bytes;
+        self.peak_arena_bytes += other.peak_arena_bytes;
+        self.used_bytes += other.used_bytes;
+        self.free_bytes += other.free_bytes;
+        self.used_blocks += other.used_blocks;
+        self.free_blocks += other.free_blocks;
+        self.largest_free = self.largest_free.max(other.largest_free);
+        self.header_bytes += other.header_bytes;
+        for (count, other) in self.free_histogram.0.iter_mut().zip(other.free_histogram.0) {
+            *count += other;
+        }
+        self.mallocs += other.mallocs;
+        self.frees += other.frees;
+        self.reallocs += other.reallocs;
+        self.splits += other.splits;
+        self.coalesces += other.coalesces;
+        self.grows += other.grows;
+        self.trims += other.trims;
+    }
+}
+
+impl<S: MemorySource> Heap<S> {
+    /// Walk the blocks and gather their totals and the heap's counters. The call counters are
+    /// left at zero, a `Heap` doesn't keep them.
+    pub fn stats(&self) -> MallocStats {
+        let counters = self.counters();
+        let mut stats = MallocStats {
+            splits: counters.splits,
+            coalesces: counters.coalesces,
+            grows: counters.grows,
+            trims: counters.trims,
+            peak_arena_bytes: counters.peak_bytes,
+            ..MallocStats::default()
+        };
+        let root = self.root();
+        if root.is_null() {
+            return stats;
+        }
+
+        stats.arena_bytes = self.source().top() - root.0 as usize;
+        stats.header_bytes = Block::get_total_padding();
+        for block in self.blocks() {
+            stats.header_bytes += Block::get_total_padding();
+            if block.is_free() {
+                stats.free_blocks += 1;
+                stats.free_bytes += block.get_data_size();
+                stats.largest_free = stats.largest_free.max(block.get_data_size());
+                stats.free_histogram.add(block.get_data_size());
+            } else {
+                stats.used_blocks += 1;
+                stats.used_bytes += block.get_data_size();
+            }
+        }
+        stats
+    }
+}
+
+/// Gather the statistics of every arena and the calls so far.
+pub fn malloc_stats() -> MallocStats {
+    let mut total = MallocStats {
+        mallocs: MALLOCS.load(Ordering::Relaxed),
+        frees: FREES.load(Ordering::Relaxed),
+        reallocs: REALLOCS.load(Ordering::Relaxed),
+        ..MallocStats::default()
+    };
+    for arena in arena::all() {
+        total.add(&arena.lock().stats());
+    }
+    total
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{malloc_stats, Histogram, MallocStats};
+    use crate::malloc::heap::Heap;
+    use crate::malloc::source::MmapRegion;
+    use crate::malloc::tests::MUTEX;
+    use crate::malloc::types::Block;
+    use crate::malloc::{free, malloc};
+
+    #[test]
+    fn test_heap_stats() {
+        let mut heap = Heap::new(MmapRegion::new(1 << 20).unwrap());
+        let first = heap.malloc(32);
+        let second = heap.malloc(256);
+        let _guard = heap.malloc(8);
+        heap.free(second);
+        // splits the free block
+        let _ = heap.malloc(64);
+        heap.free(first);
+
+        let stats = heap.stats();
+        assert_eq!(2, stats.used_blocks);
+        assert_eq!(64 + 16, stats.used_bytes);
+        assert_eq!(2, stats.free_blocks);
+        assert_eq!(32 + 256 - 64 - Block::get_total_padding(), stats.free_bytes);
+        assert_eq!(256 - 64 - Block::get_total_padding(), stats.largest_free);
+        // the root and four blocks
+        assert_eq!(5 * Block::get_total_padding(), stats.header_bytes);
+        assert_eq!(
+            stats.arena_bytes,
+            stats.used_bytes + stats.free_bytes + stats.header_bytes
+        );
+        // 32 and 168 bytes
+        assert_eq!(1, stats.free_histogram.0[5]);
+        assert_eq!(1, stats.free_histogram.0[7]);
+        assert_eq!(2, stats.free_histogram.0.iter().sum::<usize>());
+        assert_eq!(1.0 - 168.0 / 200.0, stats.fragmentation());
+        assert_eq!(1, stats.splits);
+        assert_eq!(0, stats.coalesces);
+        // the root and three blocks
+        assert_eq!(4, stats.grows);
+        assert_eq!(stats.arena_bytes, stats.peak_arena_bytes);
+    }
+
+    #[test]
+    fn test_peak_bytes() {
+        let mut heap = Heap::new(MmapRegion::new(1 << 20).unwrap());
+        let _first = heap.malloc(64);
+        let big = heap.malloc(1 << 16);
+        heap.free(big);
+        heap.trim(0);
+
+        let stats = heap.stats();
+        assert!(stats.arena_bytes < 1 << 16);
+        assert_eq!(
+            64 + (1 << 16) + 3 * Block::get_total_padding(),
+            stats.peak_arena_bytes
+        );
+    }
+
+    #[test]
+    fn test_coalesce_counter() {
+        let mut heap = Heap::new(MmapRegion::new(1 << 20).unwrap());
+        let first = heap.malloc(32);
+        let second = heap.malloc(32);
+        let third = heap.malloc(32);
+        let _guard = heap.malloc(8);
+        heap.free(first);
+        heap.free(third);
+        heap.free(second);
+        assert_eq!(2, heap.stats().coalesces);
+        assert_eq!(1, heap.stats().free_blocks);
+
+        // the tail split off the aligned block merges with the free rest of the large one
+        let large = heap.malloc(4096);
+        let _guard = heap.malloc(8);
+        heap.free(large);
+        let before = heap.stats();
+        let _aligned = heap.memalign(256, 64);
+        assert_eq!(before.coalesces + 1, heap.stats().coalesces);
+    }
+
+    #[test]
+    fn test_histogram_bucket() {
+        assert_eq!(0, Histogram::bucket(0));
+        assert_eq!(0, Histogram::bucket(1));
+        assert_eq!(4, Histogram::bucket(16));
+        assert_eq!(4, Histogram::bucket(31));
+        assert_eq!(5, Histogram::bucket(32));
+        assert_eq!(63, Histogram::bucket(usize::MAX));
+    }
+
+    #[test]
+    fn test_fragmentation() {
+        assert_eq!(0.0, MallocStats::default().fragmentation());
+        let stats = MallocStats {
+            free_bytes: 400,
+            largest_free: 100,
+            ..MallocStats::default()
+        };
+        assert_eq!(0.75, stats.fragmentation());
+    }
+
+    #[test]
+    fn test_malloc_stats() {
+        let _lock = MUTEX.lock().unwrap();
+        let before = malloc_stats();
+        let tmp = malloc(100);
+        free(tmp);
+        let after = malloc_stats();
+        assert!(after.mallocs > before.mallocs);
+        assert!(after.frees > before.frees);
+        assert!(after.arena_bytes > 0);
+    }
+}
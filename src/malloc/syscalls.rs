@@ -1,12 +1,135 @@
+// Not every arity is used yet, keep the whole set of wrappers.
+#![allow(dead_code)]
+
+use core::arch::asm;
+
 // https://github.com/kmcallister/syscall.rs/blob/master/src/platform/linux-x86_64/mod.rs
+pub const MMAP: usize = 9;
+pub const MUNMAP: usize = 11;
 pub const BRK: usize = 12;
+pub const MREMAP: usize = 25;
+pub const MADVISE: usize = 28;
+pub const GETTID: usize = 186;
+
+/// Error number of a failed system call.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Errno(pub i32);
+
+impl Errno {
+    pub const ENOMEM: Errno = Errno(12);
+    pub const EINVAL: Errno = Errno(22);
+}
 
+/// The kernel reports failure by returning `-errno`, i.e. a value in `-4095..=-1`.
 #[inline(always)]
-pub unsafe fn syscall1(n: usize, a1: usize) -> usize {
+fn decode(ret: usize) -> Result<usize, Errno> {
+    if ret > -4096isize as usize {
+        Err(Errno(-(ret as isize) as i32))
+    } else {
+        Ok(ret)
+    }
+}
+
+#[inline(always)]
+pub unsafe fn syscall0(n: usize) -> Result<usize, Errno> {
     let ret: usize;
-    llvm_asm!("syscall" : "={rax}"(ret)
-                   : "{rax}"(n), "{rdi}"(a1)
-                   : "rcx", "r11", "memory"
-                   : "volatile");
-    ret
+    asm!("syscall", inlateout("rax") n => ret,
+         out("rcx") _, out("r11") _, options(nostack));
+    decode(ret)
+}
+
+#[inline(always)]
+pub unsafe fn syscall1(n: usize, a1: usize) -> Result<usize, Errno> {
+    let ret: usize;
+    asm!("syscall", inlateout("rax") n => ret, in("rdi") a1,
+         out("rcx") _, out("r11") _, options(nostack));
+    decode(ret)
+}
+
+#[inline(always)]
+pub unsafe fn syscall2(n: usize, a1: usize, a2: usize) -> Result<usize, Errno> {
+    let ret: usize;
+    asm!("syscall", inlateout("rax") n => ret, in("rdi") a1, in("rsi") a2,
+         out("rcx") _, out("r11") _, options(nostack));
+    decode(ret)
+}
+
+#[inline(always)]
+pub unsafe fn syscall3(n: usize, a1: usize, a2: usize, a3: usize) -> Result<usize, Errno> {
+    let ret: usize;
+    asm!("syscall", inlateout("rax") n => ret, in("rdi") a1, in("rsi") a2, in("rdx") a3,
+         out("rcx") _, out("r11") _, options(nostack));
+    decode(ret)
+}
+
+#[inline(always)]
+pub unsafe fn syscall4(
+    n: usize,
+    a1: usize,
+    a2: usize,
+    a3: usize,
+    a4: usize,
+) -> Result<usize, Errno> {
+    let ret: usize;
+    asm!("syscall", inlateout("rax") n => ret, in("rdi") a1, in("rsi") a2, in("rdx") a3,
+         in("r10") a4, out("rcx") _, out("r11") _, options(nostack));
+    decode(ret)
+}
+
+#[inline(always)]
+pub unsafe fn syscall5(
+    n: usize,
+    a1: usize,
+    a2: usize,
+    a3: usize,
+    a4: usize,
+    a5: usize,
+) -> Result<usize, Errno> {
+    let ret: usize;
+    asm!("syscall", inlateout("rax") n => ret, in("rdi") a1, in("rsi") a2, in("rdx") a3,
+         in("r10") a4, in("r8") a5, out("rcx") _, out("r11") _, options(nostack));
+    decode(ret)
+}
+
+#[inline(always)]
+pub unsafe fn syscall6(
+    n: usize,
+    a1: usize,
+    a2: usize,
+    a3: usize,
+    a4: usize,
+    a5: usize,
+    a6: usize,
+) -> Result<usize, Errno> {
+    let ret: usize;
+    asm!("syscall", inlateout("rax") n => ret, in("rdi") a1, in("rsi") a2, in("rdx") a3,
+         in("r10") a4, in("r8") a5, in("r9") a6, out("rcx") _, out("r11") _, options(nostack));
+    decode(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, syscall0, syscall1, syscall3, Errno};
+
+    const GETPID: usize = 39;
+    const CLOSE: usize = 3;
+    const WRITE: usize = 1;
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(Ok(0), decode(0));
+        assert_eq!(Err(Errno::ENOMEM), decode(-12isize as usize));
+        assert_eq!(Err(Errno(4095)), decode(-4095isize as usize));
+        assert_eq!(Ok(-4096isize as usize), decode(-4096isize as usize));
+    }
+
+    #[test]
+    fn test_syscalls() {
+        unsafe {
+            assert_eq!(Ok(std::process::id() as usize), syscall0(GETPID));
+            // EBADF
+            assert_eq!(Err(Errno(9)), syscall1(CLOSE, usize::MAX));
+            assert_eq!(Ok(0), syscall3(WRITE, 1, b"".as_ptr() as usize, 0));
+        }
+    }
 }
@@ -0,0 +1,620 @@
+use core::mem::size_of;
+use core::ptr;
+
+use super::check::FreeError;
+use super::lock::SpinLock;
+use super::source::{Brk, MemorySource};
+
+const WORD: usize = size_of::<usize>();
+const FLAG_BITS: usize = WORD - 1;
+const FREE_BIT: usize = 0b01;
+/// Set while the block right below is free, which then ends with a footer.
+const PREV_FREE_BIT: usize = 0b10;
+
+/// A free block holds its header, free list links and footer.
+const MIN_BLOCK_SIZE: usize = 4 * WORD;
+
+/// Size classes of free blocks, class `i` holds sizes in `[2^(i+5), 2^(i+6))`, the last class
+/// also holds everything larger.
+const NUM_CLASSES: usize = 40;
+
+/// Serves `malloc` and friends once `Backend::BoundaryTag` is selected.
+static TAG_HEAP: SpinLock<TagHeap<Brk>> = SpinLock::new(TagHeap::new(Brk));
+
+/// A block of a `TagHeap`, pointing at its header word.
+///
+/// The header holds the size of the whole block and its flags. Only a free block repeats its
+/// size in a footer, in its last word, which is all the next block needs to find it: a block
+/// in use has one word of overhead.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Tag(*mut usize);
+
+/// A heap with Knuth's boundary tags, a more compact alternative to the block list of `Heap`.
+///
+/// Blocks have no `prev` and `next` pointers. The next block starts right after a block, the
+/// previous one is found through its footer if the `PREV_FREE_BIT` says it is free, which is
+/// all `free` needs to coalesce in constant time. A zero-sized header in use past the last
+/// block stops the walk at the end of the heap.
+///
+/// Free blocks are kept in power-of-two size classes and searched first fit.
+///
+/// ```
+/// use malloc_rs::malloc::{MmapRegion, TagHeap};
+///
+/// let mut heap = TagHeap::new(MmapRegion::new(1 << 20).unwrap());
+/// let ptr = heap.malloc(64);
+/// heap.free(ptr);
+/// ```
+pub struct TagHeap<S: MemorySource> {
+    source: S,
+    /// Address of the first block, 0 before the first allocation.
+    start: usize,
+    /// The zero-sized header past the last block.
+    end: Tag,
+    heads: [Tag; NUM_CLASSES],
+}
+
+// blocks are only reachable through the heap
+unsafe impl<S: MemorySource + Send> Send for TagHeap<S> {}
+
+pub fn global() -> &'static SpinLock<TagHeap<Brk>> {
+    &TAG_HEAP
+}
+
+/// Size of the block serving a request of `size` bytes.
+fn block_size(size: usize) -> Option<usize> {
+    let size = size.checked_add(WORD + FLAG_BITS)? & !FLAG_BITS;
+    Some(size.max(MIN_BLOCK_SIZE))
+}
+
+/// Size class of a block size, e.g. 32..=63 -> 0.
+fn class(size: usize) -> usize {
+    let log2 = (usize::BITS - 1 - size.leading_zeros()) as usize;
+    log2.saturating_sub(5).min(NUM_CLASSES - 1)
+}
+
+impl Tag {
+    const fn null() -> Tag {
+        Tag(ptr::null_mut())
+    }
+
+    fn is_null(self) -> bool {
+        self.0.is_null()
+    }
+
+    fn from_data(ptr: *mut usize) -> Tag {
+        Tag(unsafe { ptr.sub(1) })
+    }
+
+    fn data(self) -> *mut usize {
+        unsafe { self.0.add(1) }
+    }
+
+    fn size(self) -> usize {
+        unsafe { *self.0 & !FLAG_BITS }
+    }
+
+    fn is_free(self) -> bool {
+        unsafe { *self.0 & FREE_BIT != 0 }
+    }
+
+    fn is_prev_free(self) -> bool {
+        unsafe { *self.0 & PREV_FREE_BIT != 0 }
+    }
+
+    fn set_prev_free(self, prev_free: bool) {
+        unsafe {
+            if prev_free {
+                *self.0 |= PREV_FREE_BIT;
+            } else {
+                *self.0 &= !PREV_FREE_BIT;
+            }
+        }
+    }
+
+    fn next(self) -> Tag {
+        Tag((self.0 as usize + self.size()) as *mut usize)
+    }
+
+    /// The free block right below, through its footer. Only valid if `is_prev_free`.
+    fn prev(self) -> Tag {
+        let size = unsafe { *self.0.sub(1) };
+        Tag((self.0 as usize - size) as *mut usize)
+    }
+
+    /// Write the header of a block of `size` bytes, and its footer if it is free, and let the
+    /// next block know whether this one is free. The next block must have a header already.
+    fn init(self, size: usize, free: bool, prev_free: bool) {
+        let mut header = size;
+        if free {
+            header |= FREE_BIT;
+        }
+        if prev_free {
+            header |= PREV_FREE_BIT;
+        }
+        unsafe {
+            *self.0 = header;
+            if free {
+                *((self.0 as usize + size) as *mut usize).sub(1) = size;
+            }
+        }
+        self.next().set_prev_free(free);
+    }
+
+    /// Free list links, stored in the data of a free block.
+    #[allow(clippy::mut_from_ref)]
+    fn links(&self) -> &mut [Tag; 2] {
+        unsafe { &mut *(self.data() as *mut [Tag; 2]) }
+    }
+}
+
+impl<S: MemorySource> TagHeap<S> {
+    /// Create an empty heap, no memory is taken from `source` until the first allocation.
+    pub const fn new(source: S) -> TagHeap<S> {
+        TagHeap {
+            source,
+            start: 0,
+            end: Tag::null(),
+            heads: [Tag::null(); NUM_CLASSES],
+        }
+    }
+
+    pub fn source(&self) -> &S {
+        &self.source
+    }
+
+    pub(super) fn is_initialized(&self) -> bool {
+        self.start != 0
+    }
+
+    /// Whether `ptr` points into one of the blocks.
+    pub(super) fn contains(&self, ptr: *mut usize) -> bool {
+        self.is_initialized() && (self.start..self.end.0 as usize).contains(&(ptr as usize))
+    }
+
+    /// Returns null if the source is exhausted.
+    pub fn malloc(&mut self, size: usize) -> *mut usize {
+        assert!(size > 0);
+        match block_size(size).and_then(|size| self.allocate(size)) {
+            Some(tag) => tag.data(),
+            None => ptr::null_mut(),
+        }
+    }
+
+    /// Allocate zero-initialized memory for `count` elements of `size` bytes each.
+    /// Returns null if `count * size` overflows or is zero, or if the source is exhausted.
+    pub fn calloc(&mut self, count: usize, size: usize) -> *mut usize {
+        let total_size = match count.checked_mul(size) {
+            None | Some(0) => return ptr::null_mut(),
+            Some(total_size) => total_size,
+        };
+
+        let res = self.malloc(total_size);
+        if !res.is_null() {
+            unsafe { ptr::write_bytes(res as *mut u8, 0, total_size) };
+        }
+        res
+    }
+
+    /// Allocate `size` bytes at a multiple of `alignment`, which must be a power of two.
+    /// Returns null if `size` is zero, `alignment` is invalid or the source is exhausted.
+    pub fn memalign(&mut self, alignment: usize, size: usize) -> *mut usize {
+        if size == 0 || !alignment.is_power_of_two() {
+            return ptr::null_mut();
+        }
+        if alignment <= WORD {
+            return self.malloc(size);
+        }
+
+        // room to split off a free block in front of the aligned data
+        let needed = match block_size(size) {
+            Some(needed) => needed,
+            None => return ptr::null_mut(),
+        };
+        let tag = match needed
+            .checked_add(alignment + MIN_BLOCK_SIZE)
+            .and_then(|size| self.allocate(size))
+        {
+            Some(tag) => tag,
+            None => return ptr::null_mut(),
+        };
+
+        let data = tag.data() as usize;
+        if data.is_multiple_of(alignment) {
+            self.shrink(tag, needed);
+            return data as *mut usize;
+        }
+
+        let aligned = (data + MIN_BLOCK_SIZE + alignment - 1) & !(alignment - 1);
+        let inner = Tag::from_data(aligned as *mut usize);
+        let lead = inner.0 as usize - tag.0 as usize;
+        inner.init(tag.size() - lead, false, false);
+        tag.init(lead, false, tag.is_prev_free());
+        self.release(tag);
+        self.shrink(inner, needed);
+        aligned as *mut usize
+    }
+
+    /// Resize the allocation at `ptr` to `size` bytes, in place whenever possible.
+    /// A null `ptr` behaves like `malloc`, a zero `size` frees `ptr`, if any, and returns null.
+    /// Returns null, leaving `ptr` untouched, if the source is exhausted.
+    pub fn realloc(&mut self, ptr: *mut usize, size: usize) -> *mut usize {
+        if size == 0 {
+            if !ptr.is_null() {
+                self.free(ptr);
+            }
+            return ptr::null_mut();
+        }
+        if ptr.is_null() {
+            return self.malloc(size);
+        }
+        if self.resize_in_place(ptr, size) {
+            return ptr;
+        }
+
+        let new = self.malloc(size);
+        if !new.is_null() {
+            let available = self.usable_size(ptr);
+            unsafe { ptr::copy_nonoverlapping(ptr as *const u8, new as *mut u8, available) };
+            self.free(ptr);
+        }
+        new
+    }
+
+    pub fn free(&mut self, ptr: *mut usize) {
+        assert!(ptr as usize != 0);
+        self.release(Tag::from_data(ptr));
+    }
+
+    /// Make sure `ptr`, which `contains` already, starts a block in use. The blocks have no
+    /// checksum, so the tags around the block must agree with its header instead. A block that
+    /// was merged with both of its neighbours since it was freed may go unnoticed.
+    pub(super) fn check_free(&self, ptr: *mut usize) -> Result<(), FreeError> {
+        if !(ptr as usize).is_multiple_of(WORD) {
+            return Err(FreeError::Misaligned);
+        }
+        if (ptr as usize) < self.start + WORD {
+            return Err(FreeError::OutOfBounds);
+        }
+
+        let tag = Tag::from_data(ptr);
+        if !self.is_block(tag) {
+            return Err(FreeError::Corrupt);
+        }
+        if tag.is_free() {
+            return Err(FreeError::DoubleFree);
+        }
+        let next = tag.next();
+        if next.is_prev_free() {
+            // a header left behind in the free block it was merged into
+            return Err(FreeError::DoubleFree);
+        }
+        if next.is_free() && !self.is_block(next) {
+            return Err(FreeError::Corrupt);
+        }
+        if tag.is_prev_free() {
+            let prev_ends_here = tag.0 as usize != self.start
+                && unsafe { *tag.0.sub(1) } <= tag.0 as usize - self.start;
+            if !(prev_ends_here && tag.prev().is_free() && tag.prev().next() == tag) {
+                return Err(FreeError::Corrupt);
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the size of `tag` keeps it within the heap, and a free one ends with its footer.
+    fn is_block(&self, tag: Tag) -> bool {
+        let size = tag.size();
+        if size < MIN_BLOCK_SIZE || size > self.end.0 as usize - tag.0 as usize {
+            return false;
+        }
+        !tag.is_free() || unsafe { *((tag.0 as usize + size) as *mut usize).sub(1) } == size
+    }
+
+    /// Bytes of data the allocation at `ptr` can hold.
+    pub(super) fn usable_size(&self, ptr: *mut usize) -> usize {
+        Tag::from_data(ptr).size() - WORD
+    }
+
+    /// Shrink the block of `ptr` by splitting off its tail, or grow it by absorbing the free next
+    /// block or extending the source when it is the last block.
+    /// Returns false, leaving the block untouched, if neither is possible.
+    pub(super) fn resize_in_place(&mut self, ptr: *mut usize, size: usize) -> bool {
+        let needed = match block_size(size) {
+            Some(needed) => needed,
+            None => return false,
+        };
+        let tag = Tag::from_data(ptr);
+        let size = tag.size();
+        if needed <= size {
+            self.shrink(tag, needed);
+            return true;
+        }
+
+        let next = tag.next();
+        if next.is_free() && size + next.size() >= needed {
+            self.remove(next);
+            tag.init(size + next.size(), false, tag.is_prev_free());
+            self.shrink(tag, needed);
+            return true;
+        }
+        if next == self.end && self.extend(needed - size) {
+            tag.init(needed, false, tag.is_prev_free());
+            return true;
+        }
+        false
+    }
+
+    /// Place the header that ends the heap at the start of the source.
+    fn init(&mut self) -> bool {
+        let end = self.source.extend(WORD) as *mut usize;
+        if end.is_null() {
+            return false;
+        }
+
+        unsafe { *end = 0 };
+        self.start = end as usize;
+        self.end = Tag(end);
+        true
+    }
+
+    /// Take a block of `size` bytes from the free lists or the source.
+    fn allocate(&mut self, size: usize) -> Option<Tag> {
+        if !self.is_initialized() && !self.init() {
+            return None;
+        }
+
+        if let Some(tag) = self.first_fit(size) {
+            self.remove(tag);
+            tag.init(tag.size(), false, false);
+            self.shrink(tag, size);
+            return Some(tag);
+        }
+
+        // grow the free last block rather than leaving it behind
+        let last_free = self.end.is_prev_free();
+        let tag = if last_free { self.end.prev() } else { self.end };
+        let available = if last_free { tag.size() } else { 0 };
+        if !self.extend(size - available) {
+            return None;
+        }
+        if last_free {
+            self.remove(tag);
+        }
+        tag.init(size, false, false);
+        Some(tag)
+    }
+
+    /// Move the end of the heap up by `increment` bytes. The old end header must then be turned
+    /// into, or absorbed by, a block that reaches up to the new one.
+    fn extend(&mut self, increment: usize) -> bool {
+        let top = self.end.0 as usize + WORD;
+        let new = self.source.extend(increment);
+        if new.is_null() {
+            return false;
+        }
+        if new as usize != top {
+            // somebody else moved the top, the blocks would not line up. The memory is
+            // untouched, give it back, a source that cannot shrink keeps it.
+            self.source.shrink(new as usize);
+            return false;
+        }
+
+        let end = Tag((self.end.0 as usize + increment) as *mut usize);
+        unsafe { *end.0 = 0 };
+        self.end = end;
+        true
+    }
+
+    /// Split the tail off a block in use if it is at least `MIN_BLOCK_SIZE` larger than `size`,
+    /// and free it.
+    fn shrink(&mut self, tag: Tag, size: usize) {
+        let total = tag.size();
+        if total - size < MIN_BLOCK_SIZE {
+            return;
+        }
+
+        let rest = Tag((tag.0 as usize + size) as *mut usize);
+        rest.init(total - size, false, false);
+        tag.init(size, false, tag.is_prev_free());
+        self.release(rest);
+    }
+
+    /// Coalesce a block with its free neighbours and put the result in its free list.
+    fn release(&mut self, tag: Tag) {
+        let mut tag = tag;
+        let mut size = tag.size();
+
+        let next = tag.next();
+        if next.is_free() {
+            self.remove(next);
+            size += next.size();
+        }
+        if tag.is_prev_free() {
+            let prev = tag.prev();
+            self.remove(prev);
+            size += prev.size();
+            tag = prev;
+        }
+
+        // two free blocks are never neighbours, so the one below is in use
+        tag.init(size, true, false);
+        self.insert(tag);
+    }
+
+    fn first_fit(&self, size: usize) -> Option<Tag> {
+        for head in &self.heads[class(size)..] {
+            let mut current = *head;
+            while !current.is_null() {
+                if current.size() >= size {
+                    return Some(current);
+                }
+                current = current.links()[1];
+            }
+        }
+        None
+    }
+
+    /// Push a free block to the front of its size class.
+    fn insert(&mut self, tag: Tag) {
+        let head = &mut self.heads[class(tag.size())];
+        *tag.links() = [Tag::null(), *head];
+        if !head.is_null() {
+            head.links()[0] = tag;
+        }
+        *head = tag;
+    }
+
+    /// Unlink a free block. Must be called before its size changes.
+    fn remove(&mut self, tag: Tag) {
+        let [prev, next] = *tag.links();
+        if prev.is_null() {
+            self.heads[class(tag.size())] = next;
+        } else {
+            prev.links()[1] = next;
+        }
+        if !next.is_null() {
+            next.links()[0] = prev;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Tag, TagHeap, MIN_BLOCK_SIZE, WORD};
+    use crate::malloc::check::FreeError;
+    use crate::malloc::source::{MemorySource, MmapRegion};
+
+    fn heap() -> TagHeap<MmapRegion> {
+        TagHeap::new(MmapRegion::new(64 << 20).unwrap())
+    }
+
+    #[test]
+    fn test_one_word_overhead() {
+        let mut heap = heap();
+
+        let first = heap.malloc(8);
+        let second = heap.malloc(40);
+        let third = heap.malloc(8);
+        // the smallest block must hold a free block's links and footer
+        assert_eq!(first as usize + MIN_BLOCK_SIZE, second as usize);
+        assert_eq!(second as usize + 40 + WORD, third as usize);
+        assert_eq!(40, heap.usable_size(second));
+    }
+
+    #[test]
+    fn test_coalesce() {
+        let mut heap = heap();
+
+        let first = heap.malloc(64);
+        let second = heap.malloc(64);
+        let third = heap.malloc(64);
+        let guard = heap.malloc(8);
+
+        heap.free(first);
+        heap.free(third);
+        assert!(Tag::from_data(second).next().is_free());
+        assert!(!Tag::from_data(third).is_prev_free());
+        // merges with both neighbours
+        heap.free(second);
+        let merged = Tag::from_data(first);
+        assert!(merged.is_free());
+        assert_eq!(3 * (64 + WORD), merged.size());
+        assert!(Tag::from_data(guard).is_prev_free());
+
+        let top = heap.source().top();
+        assert_eq!(first, heap.malloc(3 * 64 + 2 * WORD));
+        assert_eq!(top, heap.source().top());
+    }
+
+    #[test]
+    fn test_free_last_block_is_extended() {
+        let mut heap = heap();
+
+        let first = heap.malloc(8);
+        let last = heap.malloc(64);
+        heap.free(last);
+        let top = heap.source().top();
+        assert_eq!(last, heap.malloc(128));
+        assert_eq!(top + 64, heap.source().top());
+        heap.free(first);
+    }
+
+    #[test]
+    fn test_realloc_in_place() {
+        let mut heap = heap();
+
+        let first = heap.malloc(64);
+        let second = heap.malloc(64);
+        let _guard = heap.malloc(8);
+        unsafe { *first = 42 };
+
+        heap.free(second);
+        assert_eq!(first, heap.realloc(first, 128));
+        assert_eq!(first, heap.realloc(first, 16));
+        // the tail went back to the free lists
+        assert!(Tag::from_data(first).next().is_free());
+
+        let moved = heap.realloc(first, 1024);
+        assert_ne!(first, moved);
+        assert_eq!(42, unsafe { *moved });
+        // the last block grows in place
+        assert_eq!(moved, heap.realloc(moved, 4096));
+    }
+
+    #[test]
+    fn test_memalign() {
+        let mut heap = heap();
+        let _ = heap.malloc(8);
+
+        let aligned = heap.memalign(4096, 100);
+        assert_eq!(0, aligned as usize % 4096);
+        // the slack in front was freed
+        assert!(Tag::from_data(aligned).is_prev_free());
+        heap.free(aligned);
+    }
+
+    #[test]
+    fn test_check_free() {
+        let mut heap = heap();
+
+        let first = heap.malloc(64);
+        let second = heap.malloc(64);
+        let third = heap.malloc(64);
+        let fourth = heap.malloc(64);
+        let _guard = heap.malloc(8);
+        assert_eq!(Ok(()), heap.check_free(second));
+        assert_eq!(
+            Err(FreeError::Corrupt),
+            heap.check_free(unsafe { second.add(2) })
+        );
+        assert_eq!(
+            Err(FreeError::OutOfBounds),
+            heap.check_free(heap.start as *mut usize)
+        );
+
+        heap.free(first);
+        assert_eq!(Err(FreeError::DoubleFree), heap.check_free(first));
+        // merged into `first`, its own header is left behind
+        heap.free(second);
+        assert_eq!(Err(FreeError::DoubleFree), heap.check_free(second));
+        // all four merged into one block, whose footer replaced the one of `fourth`
+        heap.free(fourth);
+        heap.free(third);
+        assert_eq!(Err(FreeError::Corrupt), heap.check_free(fourth));
+        assert_eq!(Err(FreeError::Corrupt), heap.check_free(third));
+    }
+
+    #[test]
+    fn test_calloc_zeroes_reused_block() {
+        let mut heap = heap();
+        let dirty = heap.malloc(200);
+        let _guard = heap.malloc(8);
+        unsafe { (dirty as *mut u8).write_bytes(0xff, 200) };
+        heap.free(dirty);
+
+        let zeroed = heap.calloc(25, 8);
+        assert_eq!(dirty, zeroed);
+        assert!((0..25).all(|i| unsafe { *zeroed.add(i) } == 0));
+    }
+}
@@ -0,0 +1,242 @@
+#[cfg(feature = "std")]
+use core::cell::RefCell;
+use core::mem::size_of;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::release;
+use super::types::{Block, MIN_DATA_SIZE};
+
+/// Largest data size served from the thread cache.
+pub const TCACHE_MAX_SIZE: usize = 1024;
+
+/// Blocks cached per size class, like glibc's `glibc.malloc.tcache_count` tunable.
+pub const DEFAULT_TCACHE_COUNT: usize = 7;
+static TCACHE_COUNT: AtomicUsize = AtomicUsize::new(DEFAULT_TCACHE_COUNT);
+
+/// Stored in `FreeLinks::prev_free` of cached blocks, mixed with their address, so `free` can
+/// tell a block that is freed twice from one in use, like glibc's tcache key.
+const KEY: usize = 0x7463_6163_6865_6b79;
+
+/// One class per word multiple from `MIN_DATA_SIZE` to `TCACHE_MAX_SIZE`.
+const NUM_CLASSES: usize = (TCACHE_MAX_SIZE - MIN_DATA_SIZE) / size_of::<usize>() + 1;
+
+#[derive(Clone, Copy)]
+struct Bin {
+    head: Block,
+    count: usize,
+}
+
+/// Blocks freed by this thread, kept in singly-linked lists through `FreeLinks::next_free`.
+/// They stay marked as occupied, so the shared heap never coalesces them.
+struct Tcache {
+    bins: [Bin; NUM_CLASSES],
+}
+
+#[cfg(feature = "std")]
+std::thread_local! {
+    static TCACHE: RefCell<Tcache> = const { RefCell::new(Tcache::new()) };
+}
+
+/// Cache at most `count` freed blocks per size class and thread. Zero disables the cache for
+/// blocks freed from now on.
+pub fn set_tcache_count(count: usize) {
+    TCACHE_COUNT.store(count, Ordering::Relaxed);
+}
+
+fn class(data_size: usize) -> usize {
+    (data_size - MIN_DATA_SIZE) / size_of::<usize>()
+}
+
+/// Run `f` on the cache of the current thread, unless it is gone because the thread exits or
+/// is already in use further up the stack.
+#[cfg(feature = "std")]
+fn with_tcache<R>(f: impl FnOnce(&mut Tcache) -> Option<R>) -> Option<R> {
+    TCACHE
+        .try_with(|tcache| f(&mut *tcache.try_borrow_mut().ok()?))
+        .ok()
+        .flatten()
+}
+
+/// Without std there are no thread locals, and no thread cache.
+#[cfg(not(feature = "std"))]
+fn with_tcache<R>(_f: impl FnOnce(&mut Tcache) -> Option<R>) -> Option<R> {
+    None
+}
+
+/// Pop a cached block with exactly `data_size` bytes of data, without locking.
+pub fn take(data_size: usize) -> Option<*mut usize> {
+    if data_size > TCACHE_MAX_SIZE {
+        return None;
+    }
+
+    with_tcache(|tcache| {
+        let block = tcache.bins[class(data_size)].pop()?;
+        Some(block.data().unwrap().0)
+    })
+}
+
+/// Whether `block` sits in the cache of the current thread, i.e. it was freed already.
+pub fn holds(block: &Block) -> bool {
+    let data_size = block.get_data_size();
+    if data_size > TCACHE_MAX_SIZE || block.links().prev_free != key(block) {
+        return false;
+    }
+
+    // the key may just be data that happens to match
+    with_tcache(|tcache| {
+        let mut cached = tcache.bins[class(data_size)].head;
+        while !cached.is_null() {
+            if cached == *block {
+                return Some(());
+            }
+            cached = cached.links().next_free;
+        }
+        None
+    })
+    .is_some()
+}
+
+fn key(block: &Block) -> Block {
+    Block::from_usize(block.0 as usize ^ KEY)
+}
+
+/// Cache a block that is being freed, without locking unless its size class overflows.
+/// Returns false if the block must go back to the heap instead.
+pub fn put(block: &Block) -> bool {
+    let data_size = block.get_data_size();
+    let limit = TCACHE_COUNT.load(Ordering::Relaxed);
+    if data_size > TCACHE_MAX_SIZE || limit == 0 {
+        return false;
+    }
+
+    with_tcache(|tcache| {
+        let bin = &mut tcache.bins[class(data_size)];
+        bin.push(block);
+        if bin.count > limit {
+            bin.flush(limit / 2);
+        }
+        Some(())
+    })
+    .is_some()
+}
+
+impl Bin {
+    #[cfg(feature = "std")]
+    const fn new() -> Bin {
+        Bin {
+            head: Block::null(),
+            count: 0,
+        }
+    }
+
+    fn push(&mut self, block: &Block) {
+        block.links().next_free = self.head;
+        block.links().prev_free = key(block);
+        self.head = *block;
+        self.count += 1;
+    }
+
+    fn pop(&mut self) -> Option<Block> {
+        if self.head.is_null() {
+            return None;
+        }
+
+        let block = self.head;
+        self.head = block.links().next_free;
+        block.links().prev_free = Block::null();
+        self.count -= 1;
+        Some(block)
+    }
+
+    /// Hand blocks back to the arenas they came from until `keep` are left.
+    fn flush(&mut self, keep: usize) {
+        while self.count > keep {
+            let block = self.pop().unwrap();
+            release(&block);
+        }
+    }
+}
+
+impl Tcache {
+    #[cfg(feature = "std")]
+    const fn new() -> Tcache {
+        Tcache {
+            bins: [Bin::new(); NUM_CLASSES],
+        }
+    }
+}
+
+impl Drop for Tcache {
+    /// Return everything to the heap when the thread exits.
+    fn drop(&mut self) {
+        for bin in self.bins.iter_mut() {
+            bin.flush(0);
+        }
+    }
+}
+
+// no thread cache without std
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use std::thread;
+
+    use super::{set_tcache_count, DEFAULT_TCACHE_COUNT};
+    use crate::malloc::tests::MUTEX;
+    use crate::malloc::types::Data;
+    use crate::malloc::{data_size, free, malloc};
+
+    #[test]
+    fn test_reuse_without_heap() {
+        let _lock = MUTEX.lock().unwrap();
+        let tmp = malloc(40);
+        // the block may be larger than requested if splitting it wasn't worth it
+        let data_size = Data(tmp).get_block().get_data_size();
+        free(tmp);
+
+        assert!(!Data(tmp).get_block().is_free());
+        assert_eq!(tmp, malloc(data_size));
+        free(tmp);
+    }
+
+    #[test]
+    fn test_flush_on_overflow() {
+        let _lock = MUTEX.lock().unwrap();
+        set_tcache_count(2);
+        // blocks reused from the heap may be larger, only keep three of the same size class
+        let mut blocks = Vec::new();
+        let mut others = Vec::new();
+        while blocks.len() < 3 {
+            let tmp = (malloc(72), malloc(8));
+            if Data(tmp.0).get_block().get_data_size() == data_size(72) {
+                blocks.push(tmp);
+            } else {
+                others.push(tmp);
+            }
+        }
+        for (tmp, _guard) in &blocks {
+            free(*tmp);
+        }
+
+        // the most recently cached blocks went back to the heap
+        assert!(!Data(blocks[0].0).get_block().is_free());
+        assert!(Data(blocks[1].0).get_block().is_free());
+        assert!(Data(blocks[2].0).get_block().is_free());
+        assert_eq!(blocks[0].0, malloc(72));
+        set_tcache_count(DEFAULT_TCACHE_COUNT);
+    }
+
+    #[test]
+    fn test_flush_on_thread_exit() {
+        let _lock = MUTEX.lock().unwrap();
+        let (tmp, _guard) = thread::spawn(|| {
+            let res = (malloc(104) as usize, malloc(8) as usize);
+            free(res.0 as *mut usize);
+            assert!(!Data(res.0 as *mut usize).get_block().is_free());
+            res
+        })
+        .join()
+        .unwrap();
+
+        assert!(Data(tmp as *mut usize).get_block().is_free());
+    }
+}
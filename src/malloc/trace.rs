@@ -0,0 +1,384 @@
+#[cfg(feature = "std")]
+use core::cell::{Cell, RefCell};
+#[cfg(feature = "std")]
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+#[cfg(feature = "std")]
+use std::fs::File;
+#[cfg(feature = "std")]
+use std::io::{self, Read, Write};
+#[cfg(feature = "std")]
+use std::path::Path;
+#[cfg(feature = "std")]
+use std::sync::{Mutex, OnceLock};
+#[cfg(feature = "std")]
+use std::time::Instant;
+
+use super::heap::Heap;
+use super::source::MemorySource;
+#[cfg(feature = "std")]
+use super::syscalls::{syscall0, GETTID};
+#[cfg(feature = "std")]
+use super::types::Data;
+
+/// First bytes of a trace file, followed by the version and the record size as little-endian
+/// `u32`s.
+pub const TRACE_MAGIC: [u8; 8] = *b"MALLOCRS";
+pub const TRACE_VERSION: u32 = 1;
+pub const TRACE_HEADER_SIZE: usize = 16;
+
+/// The request was served by splitting a free block.
+pub const TRACE_SPLIT: u8 = 1;
+/// The heap grew, e.g. the program break was raised, to serve the request.
+pub const TRACE_GREW: u8 = 2;
+
+/// Records a thread buffers before writing them out, 14 KiB.
+#[cfg(feature = "std")]
+const BUFFER_RECORDS: usize = 256;
+
+/// The call behind a `TraceRecord`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum TraceOp {
+    Malloc = 0,
+    /// `size` is the element size, `arg` the count.
+    Calloc = 1,
+    /// `arg` is the alignment.
+    Memalign = 2,
+    /// `arg` is the old pointer.
+    Realloc = 3,
+    /// `ptr` is the freed pointer.
+    Free = 4,
+}
+
+/// One call to `malloc` and friends, stored as `TraceRecord::SIZE` little-endian bytes: the
+/// fields in order, `op` and `flags` padded to a word.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TraceRecord {
+    /// Nanoseconds since the trace started.
+    pub timestamp: u64,
+    /// Kernel thread id of the caller.
+    pub thread: u64,
+    pub op: TraceOp,
+    /// `TRACE_SPLIT` and `TRACE_GREW`.
+    pub flags: u8,
+    pub size: u64,
+    pub arg: u64,
+    /// The returned pointer, null if the call failed.
+    pub ptr: u64,
+    /// Address of the block header behind `ptr`, 0 for slots and boundary tag blocks.
+    pub block: u64,
+}
+
+impl TraceOp {
+    fn from_u8(op: u8) -> Option<TraceOp> {
+        Some(match op {
+            0 => TraceOp::Malloc,
+            1 => TraceOp::Calloc,
+            2 => TraceOp::Memalign,
+            3 => TraceOp::Realloc,
+            4 => TraceOp::Free,
+            _ => return None,
+        })
+    }
+}
+
+impl TraceRecord {
+    pub const SIZE: usize = 56;
+
+    pub fn to_bytes(&self) -> [u8; TraceRecord::SIZE] {
+        let mut bytes = [0; TraceRecord::SIZE];
+        bytes[0..8].copy_from_slice(&self.timestamp.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.thread.to_le_bytes());
+        bytes[16] = self.op as u8;
+        bytes[17] = self.flags;
+        bytes[24..32].copy_from_slice(&self.size.to_le_bytes());
+        bytes[32..40].copy_from_slice(&self.arg.to_le_bytes());
+        bytes[40..48].copy_from_slice(&self.ptr.to_le_bytes());
+        bytes[48..56].copy_from_slice(&self.block.to_le_bytes());
+        bytes
+    }
+
+    /// Returns None if the op is unknown.
+    pub fn from_bytes(bytes: &[u8; TraceRecord::SIZE]) -> Option<TraceRecord> {
+        let word = |at: usize| {
+            let mut word = [0; 8];
+            word.copy_from_slice(&bytes[at..at + 8]);
+            u64::from_le_bytes(word)
+        };
+        Some(TraceRecord {
+            timestamp: word(0),
+            thread: word(8),
+            op: TraceOp::from_u8(bytes[16])?,
+            flags: bytes[17],
+            size: word(24),
+            arg: word(32),
+            ptr: word(40),
+            block: word(48),
+        })
+    }
+}
+
+/// The header of a trace file.
+pub fn trace_header() -> [u8; TRACE_HEADER_SIZE] {
+    let mut header = [0; TRACE_HEADER_SIZE];
+    header[0..8].copy_from_slice(&TRACE_MAGIC);
+    header[8..12].copy_from_slice(&TRACE_VERSION.to_le_bytes());
+    header[12..16].copy_from_slice(&(TraceRecord::SIZE as u32).to_le_bytes());
+    header
+}
+
+#[cfg(feature = "std")]
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// Bumped by every start and stop, records buffered under another generation are dropped.
+#[cfg(feature = "std")]
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+#[cfg(feature = "std")]
+static START: AtomicU64 = AtomicU64::new(0);
+#[cfg(feature = "std")]
+static WRITE_FAILED: AtomicBool = AtomicBool::new(false);
+#[cfg(feature = "std")]
+static FILE: Mutex<Option<File>> = Mutex::new(None);
+#[cfg(feature = "std")]
+static EPOCH: OnceLock<Instant> = OnceLock::new();
+
+/// Records of the current thread not written yet, serialized.
+#[cfg(feature = "std")]
+struct Buffer {
+    generation: u64,
+    thread: u64,
+    len: usize,
+    records: [[u8; TraceRecord::SIZE]; BUFFER_RECORDS],
+}
+
+#[cfg(feature = "std")]
+std::thread_local! {
+    static BUFFER: RefCell<Buffer> = const { RefCell::new(Buffer::new()) };
+    /// `TRACE_SPLIT` and `TRACE_GREW` seen by `observe` since the last record.
+    static FLAGS: Cell<u8> = const { Cell::new(0) };
+}
+
+#[cfg(feature = "std")]
+impl Buffer {
+    const fn new() -> Buffer {
+        Buffer {
+            generation: 0,
+            thread: 0,
+            len: 0,
+            records: [[0; TraceRecord::SIZE]; BUFFER_RECORDS],
+        }
+    }
+
+    fn push(&mut self, mut record: TraceRecord) {
+        let generation = GENERATION.load(Ordering::Acquire);
+        if self.generation != generation {
+            self.generation = generation;
+            self.len = 0;
+        }
+        if self.thread == 0 {
+            self.thread = unsafe { syscall0(GETTID) }.unwrap_or(0) as u64;
+        }
+        record.thread = self.thread;
+        self.records[self.len] = record.to_bytes();
+        self.len += 1;
+        if self.len == BUFFER_RECORDS {
+            self.flush();
+        }
+    }
+
+    /// Append the records to the trace file, unless the trace they belong to has ended.
+    fn flush(&mut self) {
+        if self.len == 0 {
+            return;
+        }
+        if let Ok(mut file) = FILE.lock() {
+            if let Some(file) = file.as_mut() {
+                if self.generation == GENERATION.load(Ordering::Acquire)
+                    && file
+                        .write_all(self.records[..self.len].as_flattened())
+                        .is_err()
+                {
+                    WRITE_FAILED.store(true, Ordering::Relaxed);
+                }
+            }
+        }
+        self.len = 0;
+    }
+}
+
+#[cfg(feature = "std")]
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+#[cfg(feature = "std")]
+fn now() -> u64 {
+    EPOCH.get_or_init(Instant::now).elapsed().as_nanos() as u64
+}
+
+#[cfg(feature = "std")]
+fn flush_current_thread() {
+    let _ = BUFFER.try_with(|buffer| {
+        if let Ok(mut buffer) = buffer.try_borrow_mut() {
+            buffer.flush();
+        }
+    });
+}
+
+/// Record every call to `malloc`, `calloc`, `memalign`, `realloc` and `free` to a new file at
+/// `path`, see `TraceRecord`. A trace that is running already ends.
+///
+/// Each thread buffers its records and writes them once the buffer is full or the thread
+/// exits, so the file is not ordered by time. Call `stop_trace` at the end to write the records
+/// of the current thread.
+#[cfg(feature = "std")]
+pub fn start_trace<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    let mut file = File::create(path)?;
+    file.write_all(&trace_header())?;
+
+    flush_current_thread();
+    let mut current = FILE.lock().unwrap_or_else(|err| err.into_inner());
+    *current = Some(file);
+    WRITE_FAILED.store(false, Ordering::Relaxed);
+    START.store(now(), Ordering::Relaxed);
+    GENERATION.fetch_add(1, Ordering::AcqRel);
+    ENABLED.store(true, Ordering::Release);
+    Ok(())
+}
+
+/// End the trace and close its file. Records still buffered by other running threads are
+/// dropped, threads that exited before have written theirs.
+#[cfg(feature = "std")]
+pub fn stop_trace() -> io::Result<()> {
+    ENABLED.store(false, Ordering::Release);
+    flush_current_thread();
+    let file = {
+        let mut current = FILE.lock().unwrap_or_else(|err| err.into_inner());
+        GENERATION.fetch_add(1, Ordering::AcqRel);
+        current.take()
+    };
+
+    if WRITE_FAILED.load(Ordering::Relaxed) {
+        return Err(io::Error::other("failed to write trace records"));
+    }
+    match file {
+        Some(file) => file.sync_all(),
+        None => Ok(()),
+    }
+}
+
+/// Read a trace file written by `start_trace`.
+#[cfg(feature = "std")]
+pub fn read_trace<R: Read>(mut reader: R) -> io::Result<Vec<TraceRecord>> {
+    let invalid = |message| io::Error::new(io::ErrorKind::InvalidData, message);
+    let mut header = [0; TRACE_HEADER_SIZE];
+    reader.read_exact(&mut header)?;
+    if header != trace_header() {
+        return Err(invalid("not a malloc_rs trace of this version"));
+    }
+
+    let mut records = Vec::new();
+    let mut bytes = [0; TraceRecord::SIZE];
+    loop {
+        match reader.read_exact(&mut bytes) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(records),
+            Err(err) => return Err(err),
+        }
+        records.push(TraceRecord::from_bytes(&bytes).ok_or_else(|| invalid("unknown op"))?);
+    }
+}
+
+/// Whether calls are being recorded.
+#[cfg(feature = "std")]
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Run an allocation on `heap` and remember for the next record whether it split a block or
+/// grew the heap.
+#[cfg(feature = "std")]
+pub fn observe<S: MemorySource, R>(heap: &mut Heap<S>, f: impl FnOnce(&mut Heap<S>) -> R) -> R {
+    if !enabled() {
+        return f(heap);
+    }
+    let before = *heap.counters();
+    let res = f(heap);
+    let after = heap.counters();
+
+    let mut flags = 0;
+    if after.splits > before.splits {
+        flags |= TRACE_SPLIT;
+    }
+    if after.grows > before.grows {
+        flags |= TRACE_GREW;
+    }
+    let _ = FLAGS.try_with(|seen| seen.set(seen.get() | flags));
+    res
+}
+
+/// Add a record of a call that returned `ptr` to the buffer of the current thread.
+#[cfg(feature = "std")]
+pub fn record(op: TraceOp, size: usize, arg: usize, ptr: *mut usize) {
+    if !enabled() {
+        return;
+    }
+    let timestamp = now().saturating_sub(START.load(Ordering::Relaxed));
+    let flags = FLAGS.try_with(|seen| seen.replace(0)).unwrap_or(0);
+    let block = if ptr.is_null() || super::slab::contains(ptr) || super::use_boundary_tags() {
+        0
+    } else {
+        Data(ptr).get_block().0 as u64
+    };
+    let record = TraceRecord {
+        timestamp,
+        thread: 0,
+        op,
+        flags,
+        size: size as u64,
+        arg: arg as u64,
+        ptr: ptr as u64,
+        block,
+    };
+    let _ = BUFFER.try_with(|buffer| {
+        if let Ok(mut buffer) = buffer.try_borrow_mut() {
+            buffer.push(record);
+        }
+    });
+}
+
+/// Without std there is no file to trace to.
+#[cfg(not(feature = "std"))]
+pub fn observe<S: MemorySource, R>(heap: &mut Heap<S>, f: impl FnOnce(&mut Heap<S>) -> R) -> R {
+    f(heap)
+}
+
+#[cfg(not(feature = "std"))]
+pub fn record(_op: TraceOp, _size: usize, _arg: usize, _ptr: *mut usize) {}
+
+#[cfg(test)]
+mod tests {
+    use super::{TraceOp, TraceRecord, TRACE_GREW};
+
+    #[test]
+    fn test_record_bytes() {
+        let record = TraceRecord {
+            timestamp: 1234,
+            thread: 42,
+            op: TraceOp::Realloc,
+            flags: TRACE_GREW,
+            size: 100,
+            arg: 0x5000,
+            ptr: 0x6000,
+            block: 0x5fe8,
+        };
+        let bytes = record.to_bytes();
+        assert_eq!(3, bytes[16]);
+        assert_eq!(Some(record), TraceRecord::from_bytes(&bytes));
+
+        let mut bytes = bytes;
+        bytes[16] = 5;
+        assert_eq!(None, TraceRecord::from_bytes(&bytes));
+    }
+}
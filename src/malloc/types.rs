@@ -1,4 +1,20 @@
-use std::mem::size_of;
+use core::mem::size_of;
+
+use super::bins::Bins;
+
+/// Free blocks must be able to hold their `FreeLinks`.
+pub const MIN_DATA_SIZE: usize = size_of::<FreeLinks>();
+
+/// Sizes are word aligned, which leaves the low bits of `Header::internal` for flags.
+const FLAG_BITS: usize = size_of::<usize>() - 1;
+const FREE_BIT: usize = 0b001;
+const MMAPPED_BIT: usize = 0b010;
+const NON_MAIN_ARENA_BIT: usize = 0b100;
+
+/// Sizes stay below 2^48 like user space addresses, which leaves the top bits of
+/// `Header::internal` for a checksum of the size, see `Header::is_intact`.
+const CHECKSUM_SHIFT: usize = 48;
+const SIZE_MASK: usize = (1 << CHECKSUM_SHIFT) - 1;
 
 #[repr(C)]
 pub struct Header {
@@ -8,26 +24,63 @@ pub struct Header {
     next: Block,
 }
 
+/// Links of the size class free list, stored at the start of a free block's data.
+#[repr(C)]
+pub struct FreeLinks {
+    pub prev_free: Block,
+    pub next_free: Block,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
 pub struct Block(pub *mut Header);
 
 pub struct Data(pub *mut usize);
 
 impl Header {
     pub fn get_size(&self) -> usize {
-        // & 1...1111000 on x86_64 system
-        self.internal & !(size_of::<usize>() - 1)
+        // & 0...01...1111000 on x86_64 system
+        self.internal & SIZE_MASK & !FLAG_BITS
     }
 
     pub fn set_size(&mut self, size: usize) {
-        self.internal = size | self.get_free_bit();
+        self.internal = Header::with_checksum(size) | (self.internal & FLAG_BITS);
+    }
+
+    /// Whether the checksum matches the size, i.e. this looks like a header written by the
+    /// allocator rather than arbitrary data.
+    pub fn is_intact(&self) -> bool {
+        Header::with_checksum(self.get_size()) == self.internal & !FLAG_BITS
+    }
+
+    fn with_checksum(size: usize) -> usize {
+        // a multiplicative hash, nonzero for an all zero header
+        let checksum = (size.wrapping_mul(0x9e37_79b9_7f4a_7c15) >> CHECKSUM_SHIFT) ^ 0xa110;
+        size | checksum << CHECKSUM_SHIFT
     }
 
     pub fn get_free_bit(&self) -> usize {
-        self.internal & 1
+        self.internal & FREE_BIT
     }
 
     pub fn set_free_bit(&mut self, free_bit: usize) {
-        self.internal = self.get_size() | free_bit;
+        self.internal = (self.internal & !FREE_BIT) | free_bit;
+    }
+
+    pub fn is_mmapped(&self) -> bool {
+        self.internal & MMAPPED_BIT != 0
+    }
+
+    pub fn set_mmapped(&mut self) {
+        self.internal |= MMAPPED_BIT;
+    }
+
+    /// Set on every block of an arena that is not backed by the program break.
+    pub fn is_non_main_arena(&self) -> bool {
+        self.internal & NON_MAIN_ARENA_BIT != 0
+    }
+
+    pub fn set_non_main_arena(&mut self) {
+        self.internal |= NON_MAIN_ARENA_BIT;
     }
 
     pub fn set_next(&mut self, block: Block) {
@@ -40,7 +93,7 @@ impl Header {
 
     // Creates new header setting the free state as 0 (occupied).
     pub fn from_usize(size: usize) -> Header {
-        Header::new(size, Block::null(), Block::null())
+        Header::new(Header::with_checksum(size), Block::null(), Block::null())
     }
 
     fn new(internal: usize, prev: Block, next: Block) -> Header {
@@ -58,7 +111,7 @@ impl Block {
         self.0 as usize == 0
     }
 
-    pub fn null() -> Block {
+    pub const fn null() -> Block {
         #[allow(clippy::zero_ptr)]
         Block(0 as *mut Header)
     }
@@ -74,6 +127,12 @@ impl Block {
         unsafe { &mut *self.0 }
     }
 
+    /// Free list links, only meaningful while the block is free.
+    #[allow(clippy::mut_from_ref)]
+    pub fn links(&'_ self) -> &'_ mut FreeLinks {
+        unsafe { &mut *((self.0 as usize + size_of::<Header>()) as *mut FreeLinks) }
+    }
+
     pub fn next_by_total_size(&self) -> Block {
         let addr = self.0 as usize;
         let next_addr = addr + self.get_total_size();
@@ -133,18 +192,18 @@ impl Block {
         self.header().set_free_bit(free_bit);
     }
 
-    /// Split block if necessary. Occupy, and return the first of the two block.
+    /// Split block if necessary and occupy the first of the two blocks.
+    /// The remaining block is coalesced with a free next block and put in its free list,
+    /// `self` must not be in one. Returns true if it was coalesced.
     /// NOTE: `data_size` doesn't include the size of the header
-    pub fn split(&'_ mut self, data_size: usize) -> &'_ mut Block {
+    pub fn split(&self, data_size: usize, bins: &mut Bins) -> bool {
         let old_total_size = self.get_total_size();
         let new_total_size = data_size + Block::get_total_padding();
 
-        // don't split if it's unnecessary!!! (e.g. only creating header on the next block)
-        if old_total_size == new_total_size
-            || old_total_size - new_total_size <= Block::get_total_padding()
-        {
+        // don't split if it's unnecessary!!! (e.g. the remaining block can't hold its free links)
+        if old_total_size - new_total_size < Block::get_total_padding() + MIN_DATA_SIZE {
             self.header().set_free_bit(0);
-            return &mut *self;
+            return false;
         }
 
         let next_block = if self.has_next() {
@@ -157,8 +216,14 @@ impl Block {
         let remaining_block = Block::from_usize(remaining_ptr);
         let remaining_total_size = old_total_size - new_total_size;
         let remaining_data_size = remaining_total_size - Block::get_total_padding();
-        remaining_block.header().set_size(remaining_data_size);
+        unsafe { *remaining_block.0 = Header::from_usize(remaining_data_size) };
         remaining_block.header().set_free_bit(1);
+        if self.header().is_non_main_arena() {
+            remaining_block.header().set_non_main_arena();
+        }
+        if !next_block.is_null() {
+            next_block.header().prev = Block::from_usize(remaining_ptr);
+        }
         remaining_block.header().next = next_block;
         remaining_block.header().prev = Block::from_usize(self.0 as usize);
 
@@ -166,39 +231,52 @@ impl Block {
         self.header().set_free_bit(0);
         self.header().next = remaining_block;
 
-        &mut *self
+        let merged = remaining_block.has_next() && remaining_block.next().is_free();
+        if merged {
+            bins.remove(remaining_block.next());
+            remaining_block.merge_next();
+        }
+        bins.insert(&remaining_block);
+
+        merged
+    }
+
+    /// Absorb the next block into this one, keeping this block's free flag.
+    /// Caller must make sure the next block exists and is not in use.
+    pub fn merge_next(&self) {
+        // copy the pointer, `self.next()` refers to the field that is overwritten below
+        let next = Block::from_usize(self.next().0 as usize);
+        self.header()
+            .set_size(self.header().get_size() + next.get_total_size());
+
+        if next.has_next() {
+            self.header().next = Block::from_usize(next.next().0 as usize);
+            let nn = self.next();
+            nn.header().prev = Block::from_usize(self.0 as usize);
+        } else {
+            self.header().next = Block::null();
+        }
     }
 
-    /// Attempts to join next and previous block if it's free.
+    /// Attempts to join next and previous block if it's free, and puts the result in its free list.
     /// This function is called after every `free` in order to look forward / backward only once.
-    pub fn coalesce(&self) {
+    /// Returns the block `self` ended up in.
+    pub fn coalesce(&self, bins: &mut Bins) -> Block {
         if self.has_next() && self.next().is_free() {
-            let next = self.next();
-            self.header()
-                .set_size(self.header().get_size() + next.get_total_size());
-
-            if next.has_next() {
-                self.header().next = Block::from_usize(next.next().0 as usize);
-                let nn = next.next();
-                nn.header().prev = Block::from_usize(next.0 as usize);
-            } else {
-                self.header().next = Block::null();
-            }
+            bins.remove(self.next());
+            self.merge_next();
         }
 
         if self.has_prev() && self.prev().is_free() {
-            let prev = self.prev();
-            prev.header()
-                .set_size(prev.get_data_size() + self.get_total_size());
-
-            if self.has_next() {
-                prev.header().next = Block::from_usize(self.next().0 as usize);
-                let next = self.next();
-                next.header().prev = Block::from_usize(prev.0 as usize);
-            } else {
-                prev.header().next = Block::null();
-            }
+            let prev = *self.prev();
+            bins.remove(&prev);
+            prev.merge_next();
+            bins.insert(&prev);
+            return prev;
         }
+
+        bins.insert(self);
+        *self
     }
 }
 
@@ -236,4 +314,25 @@ mod tests {
         let block = Block(&header as *const Header as *mut Header);
         assert_eq!(16, block.header().get_size());
     }
+
+    #[test]
+    fn test_header_checksum() {
+        let mut header = Header::from_usize(48);
+        header.set_free_bit(1);
+        header.set_mmapped();
+        assert!(header.is_intact());
+        assert_eq!(48, header.get_size());
+
+        header.set_size(4096);
+        assert!(header.is_intact());
+        assert_eq!(4096, header.get_size());
+
+        // e.g. a pointer into the middle of an allocation
+        let zeroed: [usize; 3] = [0; 3];
+        let block = Block(&zeroed as *const usize as *mut Header);
+        assert!(!block.header().is_intact());
+        let data = [4096usize, 0, 0];
+        let block = Block(&data as *const usize as *mut Header);
+        assert!(!block.header().is_intact());
+    }
 }
@@ -0,0 +1,249 @@
+use core::fmt;
+use core::mem::size_of;
+
+use super::arena;
+use super::heap::Heap;
+use super::source::MemorySource;
+use super::types::Block;
+
+/// Totals of a heap that passed `verify_heap`. The sentinel root block is not counted.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HeapReport {
+    pub blocks: usize,
+    pub free_blocks: usize,
+    /// Data bytes of the blocks in use, including those held by thread caches.
+    pub used_bytes: usize,
+    /// Data bytes of the free blocks.
+    pub free_bytes: usize,
+}
+
+/// What is wrong with a block.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Corruption {
+    /// The header is not word aligned.
+    Misaligned,
+    /// The block reaches past the top of the heap's memory.
+    OutOfBounds,
+    /// `next` is not where the size of the block says the next block starts.
+    NextMismatch,
+    /// `prev` of the next block doesn't point back at the block.
+    PrevMismatch,
+    /// The block and the next one are both free, they should have been coalesced.
+    AdjacentFree,
+    /// The last block doesn't end at the top of the heap's memory, e.g. the program break.
+    EndMismatch,
+    /// The heap appends new blocks after another block than the last one.
+    LastMismatch,
+}
+
+/// The first inconsistency found by `verify_heap`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HeapCorruption {
+    /// Address of the offending block's header.
+    pub block: usize,
+    pub kind: Corruption,
+}
+
+impl fmt::Display for Corruption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Corruption::Misaligned => "header is not word aligned",
+            Corruption::OutOfBounds => "block reaches past the top of the heap",
+            Corruption::NextMismatch => "next pointer doesn't match the block size",
+            Corruption::PrevMismatch => "next block doesn't point back",
+            Corruption::AdjacentFree => "block and next block are both free",
+            Corruption::EndMismatch => "last block doesn't end at the top of the heap",
+            Corruption::LastMismatch => "block is not the heap's last block",
+        })
+    }
+}
+
+impl fmt::Display for HeapCorruption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "heap corruption at {:#x}: {}", self.block, self.kind)
+    }
+}
+
+impl<S: MemorySource> Heap<S> {
+    /// Walk the block list from the root and check that it is consistent, see `Corruption`.
+    /// Reports the first offending block.
+    pub fn verify(&self) -> Result<HeapReport, HeapCorruption> {
+        let mut report = HeapReport::default();
+        let root = self.root();
+        if root.is_null() {
+            return Ok(report);
+        }
+
+        let top = self.source().top();
+        let mut block = root;
+        loop {
+            let corrupt = |kind| {
+                Err(HeapCorruption {
+                    block: block.0 as usize,
+                    kind,
+                })
+            };
+            if !(block.0 as usize).is_multiple_of(size_of::<usize>()) {
+                return corrupt(Corruption::Misaligned);
+            }
+            // read nothing of a block past the top
+            let end = (block.0 as usize).checked_add(block.get_total_size());
+            if end.is_none_or(|end| end > top) {
+                return corrupt(Corruption::OutOfBounds);
+            }
+
+            if block != root {
+                report.blocks += 1;
+                if block.is_free() {
+                    report.free_blocks += 1;
+                    report.free_bytes += block.get_data_size();
+                } else {
+                    report.used_bytes += block.get_data_size();
+                }
+            }
+
+            if !block.has_next() {
+                if block != self.last() {
+                    return corrupt(Corruption::LastMismatch);
+                }
+                if end != Some(top) {
+                    return corrupt(Corruption::EndMismatch);
+                }
+                return Ok(report);
+            }
+
+            let next = *block.next();
+            if next != block.next_by_total_size()
+                || next.0 as usize + Block::get_total_padding() > top
+            {
+                return corrupt(Corruption::NextMismatch);
+            }
+            if *next.prev() != block {
+                return corrupt(Corruption::PrevMismatch);
+            }
+            if block.is_free() && next.is_free() {
+                return corrupt(Corruption::AdjacentFree);
+            }
+            block = next;
+        }
+    }
+}
+
+/// Check the heap of every arena, see `Heap::verify`, and add up their reports.
+///
+/// Only the block lists of `Backend::BlockList` are checked, slabs and mmapped blocks are not.
+pub fn verify_heap() -> Result<HeapReport, HeapCorruption> {
+    let mut total = HeapReport::default();
+    for arena in arena::all() {
+        let report = arena.lock().verify()?;
+        total.blocks += report.blocks;
+        total.free_blocks += report.free_blocks;
+        total.used_bytes += report.used_bytes;
+        total.free_bytes += report.free_bytes;
+    }
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{verify_heap, Corruption, HeapCorruption, HeapReport};
+    use crate::malloc::heap::Heap;
+    use crate::malloc::source::MmapRegion;
+    use crate::malloc::tests::MUTEX;
+    use crate::malloc::types::{Block, Data};
+    use crate::malloc::{free, malloc};
+
+    fn heap() -> Heap<MmapRegion> {
+        Heap::new(MmapRegion::new(1 << 20).unwrap())
+    }
+
+    /// A heap of three blocks, the middle one free.
+    fn three_blocks(heap: &mut Heap<MmapRegion>) -> [Block; 3] {
+        let blocks = [heap.malloc(32), heap.malloc(64), heap.malloc(16)];
+        heap.free(blocks[1]);
+        blocks.map(|ptr| Data(ptr).get_block())
+    }
+
+    #[test]
+    fn test_consistent_heap() {
+        let mut heap = heap();
+        assert_eq!(Ok(HeapReport::default()), heap.verify());
+
+        three_blocks(&mut heap);
+        assert_eq!(
+            Ok(HeapReport {
+                blocks: 3,
+                free_blocks: 1,
+                used_bytes: 48,
+                free_bytes: 64,
+            }),
+            heap.verify()
+        );
+    }
+
+    #[test]
+    fn test_corrupt_next() {
+        let mut heap = heap();
+        let [first, _, last] = three_blocks(&mut heap);
+        *first.next() = last;
+        assert_eq!(
+            Err(HeapCorruption {
+                block: first.0 as usize,
+                kind: Corruption::NextMismatch,
+            }),
+            heap.verify()
+        );
+    }
+
+    #[test]
+    fn test_corrupt_prev() {
+        let mut heap = heap();
+        let [first, free, _] = three_blocks(&mut heap);
+        *free.prev() = Block::null();
+        assert_eq!(
+            Err(HeapCorruption {
+                block: first.0 as usize,
+                kind: Corruption::PrevMismatch,
+            }),
+            heap.verify()
+        );
+    }
+
+    #[test]
+    fn test_adjacent_free() {
+        let mut heap = heap();
+        let [first, _, _] = three_blocks(&mut heap);
+        first.set_free(true);
+        let err = heap.verify().unwrap_err();
+        assert_eq!(Corruption::AdjacentFree, err.kind);
+        assert_eq!(first.0 as usize, err.block);
+    }
+
+    #[test]
+    fn test_corrupt_size() {
+        let mut heap = heap();
+        let [first, _, last] = three_blocks(&mut heap);
+
+        first.header().set_size(40);
+        assert_eq!(Corruption::NextMismatch, heap.verify().unwrap_err().kind);
+        first.header().set_size(32);
+
+        last.header().set_size(1 << 20);
+        let err = heap.verify().unwrap_err();
+        assert_eq!(Corruption::OutOfBounds, err.kind);
+        assert_eq!(last.0 as usize, err.block);
+        last.header().set_size(8);
+        assert_eq!(Corruption::EndMismatch, heap.verify().unwrap_err().kind);
+    }
+
+    #[test]
+    fn test_verify_heap() {
+        let _lock = MUTEX.lock().unwrap();
+        let tmp = malloc(100);
+        let report = verify_heap().unwrap();
+        assert!(report.blocks > 0);
+        assert!(report.used_bytes >= 104);
+        free(tmp);
+        assert!(verify_heap().is_ok());
+    }
+}
@@ -1,4 +1,4 @@
-use crate::malloc::{free, malloc};
+use crate::malloc::{free, malloc, SlabCache};
 use lazy_static::lazy_static;
 use std::mem::size_of;
 use std::sync::Mutex;
@@ -23,6 +23,7 @@ pub struct Queue<T> {
     head_segment: *mut Segment,
     tail_segment: *mut Segment,
     size: *mut usize,
+    segments: SlabCache<Segment>,
 }
 
 impl Segment {
@@ -35,8 +36,9 @@ impl<T> Queue<T> {
     #[allow(dead_code)]
     #[allow(clippy::zero_ptr)]
     pub fn new() -> Queue<T> {
+        let mut segments = SlabCache::new();
         let head = malloc(INITIAL_CAPACITY * size_of::<T>()) as *mut T;
-        let head_segment_ptr = malloc(size_of::<Segment>()) as *mut Segment;
+        let head_segment_ptr = segments.alloc();
         let size_ptr = malloc(size_of::<usize>());
         // println!("new {:?} {:?}", head as usize, head_segment_ptr as usize);
         unsafe {
@@ -53,6 +55,7 @@ impl<T> Queue<T> {
             head_segment: head_segment_ptr,
             tail_segment: head_segment_ptr,
             size: size_ptr,
+            segments,
         }
     }
 
@@ -79,7 +82,7 @@ impl<T> Queue<T> {
             self.head_segment = head_segment.next;
             self.head = next.origin as *mut T;
             free(head_segment.origin);
-            free(head_segment_ptr as *mut usize);
+            self.segments.free(head_segment_ptr);
         } else {
             // println!("not last block origin {:?}", self.head as usize);
             self.head = (self.head as usize + size_of::<T>()) as *mut T;
@@ -125,7 +128,7 @@ impl<T> Queue<T> {
     fn allocate_next(&mut self) {
         // println!("start allocate {:?} * {:?}", CAPACITY_INC, size_of::<T>());
         let origin = malloc(CAPACITY_INC * size_of::<T>()) as *mut T;
-        let segment = malloc(size_of::<Segment>()) as *mut Segment;
+        let segment = self.segments.alloc();
         unsafe {
             *segment = Segment {
                 next: 0 as *mut Segment,
@@ -134,7 +137,7 @@ impl<T> Queue<T> {
             };
         }
 
-        let mut tail_segment = unsafe { &mut *self.tail_segment };
+        let tail_segment = unsafe { &mut *self.tail_segment };
         tail_segment.next = segment;
     }
 
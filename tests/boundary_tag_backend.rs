@@ -0,0 +1,43 @@
+use malloc_rs::malloc::{
+    calloc, free, invalid_frees, malloc, memalign, realloc, set_backend, set_free_check, Backend,
+    FreeCheck,
+};
+
+#[test]
+fn test_boundary_tag_backend() {
+    assert!(set_backend(Backend::BoundaryTag));
+
+    let first = malloc(24);
+    let second = malloc(24);
+    // one word of header in between
+    assert_eq!(first as usize + 32, second as usize);
+
+    let aligned = memalign(256, 100);
+    assert_eq!(0, aligned as usize % 256);
+    unsafe { *aligned = 42 };
+    let grown = realloc(aligned, 1000);
+    assert_eq!(42, unsafe { *grown });
+
+    // mmapped blocks are told apart from the heap's
+    let large = calloc(1 << 20, 1);
+    assert_eq!(0, unsafe { *large.add(1000) });
+    free(large);
+
+    free(first);
+    free(second);
+    free(grown);
+
+    // a double free is refused instead of handing the block out twice
+    set_free_check(FreeCheck::Ignore);
+    let once = malloc(48);
+    let _guard = malloc(48);
+    free(once);
+    free(once);
+    assert_eq!(1, invalid_frees());
+    assert!(realloc(once, 100).is_null());
+    assert_eq!(2, invalid_frees());
+    assert_ne!(malloc(48), malloc(48));
+
+    // too late to switch back
+    assert!(!set_backend(Backend::BlockList));
+}
@@ -0,0 +1,34 @@
+use malloc_rs::malloc::{
+    free, invalid_frees, malloc, memalign, realloc, set_backend, set_free_check, Backend, FreeCheck,
+};
+
+#[test]
+fn test_buddy_backend() {
+    assert!(set_backend(Backend::Buddy));
+
+    let small = malloc(8);
+    let other = malloc(8);
+    // buddies of 32 bytes
+    assert_eq!(small as usize + 32, other as usize);
+
+    let aligned = memalign(256, 100);
+    assert_eq!(0, aligned as usize % 256);
+    unsafe { *aligned = 42 };
+    let moved = realloc(aligned, 1000);
+    assert_eq!(42, unsafe { *moved });
+
+    free(small);
+    free(other);
+    free(moved);
+
+    // the header in front of aligned data is marked free as well as the block around it
+    set_free_check(FreeCheck::Ignore);
+    let aligned = memalign(256, 100);
+    free(aligned);
+    free(aligned);
+    assert_eq!(1, invalid_frees());
+    assert_ne!(memalign(256, 100), memalign(256, 100));
+
+    // too late to switch back
+    assert!(!set_backend(Backend::BlockList));
+}
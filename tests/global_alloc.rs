@@ -0,0 +1,42 @@
+use std::collections::HashMap;
+use std::thread;
+
+use malloc_rs::malloc::Malloc;
+
+#[global_allocator]
+static GLOBAL: Malloc = Malloc;
+
+#[test]
+fn test_std_collections() {
+    let mut map = HashMap::new();
+    for i in 0..1000 {
+        map.insert(i, format!("value {}", i));
+    }
+    assert_eq!("value 999", map[&999]);
+
+    let mut v: Vec<u64> = (0..10_000).collect();
+    v.retain(|x| x % 3 == 0);
+    assert_eq!(3334, v.len());
+}
+
+#[test]
+fn test_threads() {
+    let handles: Vec<_> = (0..4)
+        .map(|t| {
+            thread::spawn(move || {
+                let mut strings = Vec::new();
+                for i in 0..500 {
+                    strings.push(format!("{}-{}", t, i));
+                    if i % 3 == 0 {
+                        strings.remove(0);
+                    }
+                }
+                strings.len()
+            })
+        })
+        .collect();
+
+    for handle in handles {
+        assert_eq!(333, handle.join().unwrap());
+    }
+}
@@ -0,0 +1,29 @@
+use std::ptr;
+
+use malloc_rs::malloc::{calloc, free, malloc, set_heap_buffer};
+
+const BUFFER_SIZE: usize = 64 * 1024;
+static mut BUFFER: [u8; BUFFER_SIZE] = [0xff; BUFFER_SIZE];
+static mut OTHER: [u8; 1024] = [0; 1024];
+
+#[test]
+fn test_heap_buffer() {
+    let buffer = unsafe { &mut *ptr::addr_of_mut!(BUFFER) };
+    let range = buffer.as_ptr_range();
+    assert!(set_heap_buffer(buffer));
+
+    let tmp = malloc(100);
+    assert!(range.contains(&(tmp as *const u8)));
+    // the buffer was dirty, so fresh blocks are cleared too
+    let zeroed = calloc(16, 8);
+    assert!(range.contains(&(zeroed as *const u8)));
+    assert!((0..16).all(|i| unsafe { *zeroed.add(i) } == 0));
+
+    // no mmap fallback for what doesn't fit
+    assert!(malloc(BUFFER_SIZE).is_null());
+    free(tmp);
+    free(zeroed);
+
+    // too late to switch buffers
+    assert!(!set_heap_buffer(unsafe { &mut *ptr::addr_of_mut!(OTHER) }));
+}
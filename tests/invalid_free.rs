@@ -0,0 +1,82 @@
+use std::env;
+use std::process::Command;
+
+use malloc_rs::malloc::{
+    free, invalid_frees, malloc, malloc_stats, realloc, set_free_check, set_slab_max,
+    set_tcache_count, verify_heap, FreeCheck,
+};
+
+#[test]
+fn test_invalid_frees_are_skipped() {
+    set_free_check(FreeCheck::Ignore);
+
+    // caught by the tcache key
+    let cached = malloc(32);
+    free(cached);
+    free(cached);
+    assert_eq!(1, invalid_frees());
+
+    // caught by the free bit
+    set_tcache_count(0);
+    let first = malloc(48);
+    let guard = malloc(48);
+    free(first);
+    free(first);
+    assert_eq!(2, invalid_frees());
+    assert!(realloc(first, 100).is_null());
+    assert_eq!(3, invalid_frees());
+
+    // a slot freed twice
+    set_slab_max(64);
+    let slot = malloc(16);
+    free(slot);
+    free(slot);
+    assert_eq!(4, invalid_frees());
+    set_slab_max(0);
+
+    // not a heap pointer at all
+    let mut local = [0usize; 4];
+    free(&mut local[3]);
+    free((guard as usize + 1) as *mut usize);
+    assert_eq!(6, invalid_frees());
+
+    // only the valid free is counted, the double free after it is not
+    let frees = malloc_stats().frees;
+    free(guard);
+    assert_eq!(6, invalid_frees());
+    assert_eq!(frees + 1, malloc_stats().frees);
+    free(guard);
+    assert_eq!(7, invalid_frees());
+    assert_eq!(frees + 1, malloc_stats().frees);
+
+    // the heap survived all of it
+    assert!(verify_heap().is_ok());
+    let again = malloc(48);
+    assert_eq!(first, again);
+    free(again);
+    assert_eq!(7, invalid_frees());
+}
+
+#[test]
+fn test_double_free_aborts() {
+    if env::var_os("MALLOC_RS_DOUBLE_FREE").is_some() {
+        let ptr = malloc(64);
+        free(ptr);
+        free(ptr);
+        unreachable!("the double free went unnoticed");
+    }
+
+    // the abort takes the process with it, run this test again in a child
+    let output = Command::new(env::current_exe().unwrap())
+        .args(["--exact", "test_double_free_aborts", "--nocapture"])
+        .env("MALLOC_RS_DOUBLE_FREE", "1")
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("malloc_rs: free(): double free at 0x"),
+        "{}",
+        stderr
+    );
+}
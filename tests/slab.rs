@@ -0,0 +1,32 @@
+use malloc_rs::malloc::{calloc, free, malloc, realloc, set_slab_max};
+
+#[test]
+fn test_malloc_from_slabs() {
+    set_slab_max(64);
+
+    let first = malloc(24);
+    let second = malloc(24);
+    // neighbouring slots without a header in between
+    assert_eq!(first as usize + 24, second as usize);
+    unsafe { *first = 42 };
+
+    // still fits the slot
+    assert_eq!(first, realloc(first, 20));
+    let grown = realloc(first, 1000);
+    assert_eq!(42, unsafe { *grown });
+
+    // the lowest free slot is taken first
+    unsafe { *second = 43 };
+    free(second);
+    let zeroed = calloc(3, 8);
+    assert_eq!(first, zeroed);
+    assert!((0..3).all(|i| unsafe { *zeroed.add(i) } == 0));
+
+    // `second` is free, but slabs are off
+    set_slab_max(0);
+    let other = malloc(24);
+    assert_ne!(second, other);
+    free(other);
+    free(zeroed);
+    free(grown);
+}
@@ -0,0 +1,84 @@
+use std::env;
+use std::fs::{self, File};
+use std::process;
+use std::thread;
+
+use malloc_rs::malloc::{
+    calloc, free, malloc, memalign, read_trace, realloc, start_trace, stop_trace, TraceOp,
+    TraceRecord, TRACE_GREW, TRACE_SPLIT,
+};
+
+#[test]
+fn test_trace() {
+    let path = env::temp_dir().join(format!("malloc_rs_trace_{}", process::id()));
+    // not traced
+    free(malloc(64));
+
+    start_trace(&path).unwrap();
+    let first = malloc(100);
+    let guard = malloc(16);
+    let grown = realloc(first, 50);
+    let zeroed = calloc(4, 8);
+    let aligned = memalign(256, 64);
+    thread::spawn(|| free(malloc(32))).join().unwrap();
+    free(zeroed);
+    free(aligned);
+    free(grown);
+    free(guard);
+    stop_trace().unwrap();
+    // not traced either
+    free(malloc(64));
+
+    let records = read_trace(File::open(&path).unwrap()).unwrap();
+    fs::remove_file(&path).unwrap();
+
+    // the other thread wrote its records when it exited
+    let main = records.last().unwrap().thread;
+    let (ours, theirs): (Vec<&TraceRecord>, Vec<&TraceRecord>) =
+        records.iter().partition(|r| r.thread == main);
+    assert_eq!(
+        vec![TraceOp::Malloc, TraceOp::Free],
+        theirs.iter().map(|r| r.op).collect::<Vec<_>>()
+    );
+    assert_ne!(0, theirs[0].thread);
+
+    let ops: Vec<_> = ours.iter().map(|r| r.op).collect();
+    assert_eq!(
+        vec![
+            TraceOp::Malloc,
+            TraceOp::Malloc,
+            TraceOp::Realloc,
+            TraceOp::Calloc,
+            TraceOp::Memalign,
+            TraceOp::Free,
+            TraceOp::Free,
+            TraceOp::Free,
+            TraceOp::Free,
+        ],
+        ops
+    );
+    assert!(ours.windows(2).all(|w| w[0].timestamp <= w[1].timestamp));
+
+    let [malloc_record, _, realloc_record, calloc_record, memalign_record, free_record, ..] =
+        ours[..]
+    else {
+        unreachable!()
+    };
+    assert_eq!(100, malloc_record.size);
+    assert_eq!(first as u64, malloc_record.ptr);
+    assert_eq!(first as u64 - 24, malloc_record.block);
+
+    // shrunk in place by splitting
+    assert_eq!(first as u64, realloc_record.arg);
+    assert_eq!(grown as u64, realloc_record.ptr);
+    assert_eq!(50, realloc_record.size);
+    assert_ne!(0, realloc_record.flags & TRACE_SPLIT);
+
+    assert_eq!((8, 4), (calloc_record.size, calloc_record.arg));
+    assert_eq!((64, 256), (memalign_record.size, memalign_record.arg));
+    assert_eq!(aligned as u64, memalign_record.ptr);
+    assert_eq!(zeroed as u64, free_record.ptr);
+    assert!(records
+        .iter()
+        .all(|r| r.flags & !(TRACE_SPLIT | TRACE_GREW) == 0));
+}